@@ -0,0 +1,515 @@
+use crate::block_registry::BlockRegistry;
+use crate::chunk::{BlockID, ChunkData};
+use crate::chunk_manager::{ChunkManager, CHUNK_SIZE, CHUNK_VOLUME};
+use crate::nbt::{read_nbt, Tag};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Importer for vanilla Minecraft Anvil region files (r.<x>.<z>.mca, Java Edition 1.13+).
+// https://minecraft.wiki/w/Anvil_file_format
+
+const SECTOR_SIZE: usize = 4096;
+const REGION_CHUNKS: usize = 32 * 32;
+
+// From 20w17a (1.16) on, block indices no longer span two longs
+const DATA_VERSION_NO_SPANNING: i64 = 2529;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+// Vanilla block name ("minecraft:grass_block") -> BlockID
+pub struct BlockMapping {
+    names: HashMap<String, BlockID>,
+    // Used for vanilla blocks with no mapping. None: they become air.
+    pub fallback: Option<BlockID>,
+}
+
+impl BlockMapping {
+    // "minecraft:<name>" maps to the registry block called <name>, plus a few look-alikes
+    pub fn from_registry(registry: &BlockRegistry, fallback: Option<BlockID>) -> BlockMapping {
+        let mut mapping = BlockMapping {
+            names: HashMap::new(),
+            fallback,
+        };
+
+        for (id, definition) in registry.iter() {
+            mapping.names.insert(format!("minecraft:{}", definition.name), id);
+        }
+
+        for air in ["minecraft:air", "minecraft:cave_air", "minecraft:void_air"] {
+            mapping.names.insert(air.to_owned(), BlockID::AIR);
+        }
+
+        let aliases = [
+            ("minecraft:deepslate", "stone"),
+            ("minecraft:andesite", "stone"),
+            ("minecraft:diorite", "stone"),
+            ("minecraft:granite", "stone"),
+            ("minecraft:tuff", "stone"),
+            ("minecraft:deepslate_coal_ore", "coal_ore"),
+            ("minecraft:deepslate_iron_ore", "iron_ore"),
+            ("minecraft:deepslate_gold_ore", "gold_ore"),
+            ("minecraft:deepslate_diamond_ore", "diamond_ore"),
+            ("minecraft:coarse_dirt", "dirt"),
+            ("minecraft:podzol", "dirt"),
+            ("minecraft:rooted_dirt", "dirt"),
+            ("minecraft:crying_obsidian", "obsidian"),
+            ("minecraft:oak_wood", "oak_log"),
+        ];
+
+        for (vanilla, name) in aliases {
+            if let Some(id) = registry.by_name(name) {
+                mapping.names.entry(vanilla.to_owned()).or_insert(id);
+            }
+        }
+
+        mapping
+    }
+
+    pub fn insert(&mut self, vanilla_name: &str, block: BlockID) {
+        self.names.insert(vanilla_name.to_owned(), block);
+    }
+
+    pub fn map(&self, vanilla_name: &str) -> BlockID {
+        self.names
+            .get(vanilla_name)
+            .copied()
+            .or(self.fallback)
+            .unwrap_or(BlockID::AIR)
+    }
+}
+
+// Every chunk stored in an .mca file, decompressed and parsed
+pub fn read_region(bytes: &[u8]) -> io::Result<Vec<Tag>> {
+    if bytes.len() < 2 * SECTOR_SIZE {
+        return Err(invalid("truncated region header"));
+    }
+
+    let mut chunks = Vec::new();
+
+    for i in 0..REGION_CHUNKS {
+        let entry = u32::from_be_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        let (sector, sector_count) = ((entry >> 8) as usize, (entry & 0xff) as usize);
+
+        if sector == 0 || sector_count == 0 {
+            continue;
+        }
+
+        let start = sector * SECTOR_SIZE;
+        let header = bytes.get(start..start + 5).ok_or_else(|| invalid("chunk out of bounds"))?;
+        let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let compression = header[4];
+
+        if length == 0 {
+            return Err(invalid("empty chunk payload"));
+        }
+
+        let payload = bytes
+            .get(start + 5..start + 4 + length)
+            .ok_or_else(|| invalid("chunk out of bounds"))?;
+
+        let (_, root) = match compression {
+            1 => read_nbt(GzDecoder::new(payload))?,
+            2 => read_nbt(ZlibDecoder::new(payload))?,
+            3 => read_nbt(payload)?,
+            // Oversized chunks live in separate .mcc files, LZ4 is 24w04a+
+            c if c & 0x80 != 0 => return Err(invalid("external chunk files are not supported")),
+            _ => return Err(invalid("unsupported chunk compression")),
+        };
+
+        chunks.push(root);
+    }
+
+    Ok(chunks)
+}
+
+// Unpacks the palette indices of one 16x16x16 section
+fn unpack_indices(data: &[i64], palette_len: usize, spanning: bool) -> io::Result<Vec<usize>> {
+    let bits = usize::max(4, (usize::BITS - (palette_len - 1).leading_zeros()) as usize);
+    let mask = (1u64 << bits) - 1;
+    let mut indices = Vec::with_capacity(CHUNK_VOLUME as usize);
+
+    for i in 0..CHUNK_VOLUME as usize {
+        let value = if spanning {
+            let bit = i * bits;
+            let (word, shift) = (bit / 64, bit % 64);
+            let low = *data.get(word).ok_or_else(|| invalid("block data too short"))? as u64 >> shift;
+
+            if shift + bits > 64 {
+                let high = *data.get(word + 1).ok_or_else(|| invalid("block data too short"))? as u64;
+                (low | (high << (64 - shift))) & mask
+            } else {
+                low & mask
+            }
+        } else {
+            let per_word = 64 / bits;
+            let word = *data.get(i / per_word).ok_or_else(|| invalid("block data too short"))? as u64;
+            (word >> ((i % per_word) * bits)) & mask
+        };
+
+        if value as usize >= palette_len {
+            return Err(invalid("palette index out of range"));
+        }
+
+        indices.push(value as usize);
+    }
+
+    Ok(indices)
+}
+
+// (chunk y, blocks) of every section of a chunk that isn't entirely air
+pub fn read_sections(chunk: &Tag, mapping: &BlockMapping) -> io::Result<Vec<(i32, ChunkData)>> {
+    let data_version = chunk.get("DataVersion").and_then(Tag::as_i64).unwrap_or(0);
+
+    // Before 21w43a (1.18) everything was nested in "Level"
+    let (sections, palette_key, data_key) = match chunk.get("sections") {
+        Some(sections) => (sections, None, "data"),
+        None => (
+            chunk
+                .get("Level")
+                .and_then(|level| level.get("Sections"))
+                .ok_or_else(|| invalid("chunk has no sections"))?,
+            Some("Palette"),
+            "BlockStates",
+        ),
+    };
+
+    let mut result = Vec::new();
+
+    for section in sections.as_list().unwrap_or(&[]) {
+        let y = section.get("Y").and_then(Tag::as_i64).ok_or_else(|| invalid("section without Y"))? as i32;
+
+        let (palette, data) = match palette_key {
+            Some(key) => (section.get(key), section.get(data_key)),
+            None => match section.get("block_states") {
+                Some(states) => (states.get("palette"), states.get(data_key)),
+                None => (None, None),
+            },
+        };
+
+        // Light-only sections above and below the world
+        let palette = match palette.and_then(Tag::as_list) {
+            Some(palette) if !palette.is_empty() => palette,
+            _ => continue,
+        };
+
+        let blocks: Vec<BlockID> = palette
+            .iter()
+            .map(|state| mapping.map(state.get("Name").and_then(Tag::as_str).unwrap_or("minecraft:air")))
+            .collect();
+
+        if blocks.iter().all(|b| b.is_air()) {
+            continue;
+        }
+
+        let mut chunk_data = ChunkData::empty();
+
+        match data.and_then(Tag::as_long_array) {
+            Some(data) if blocks.len() > 1 => {
+                let indices = unpack_indices(data, blocks.len(), data_version < DATA_VERSION_NO_SPANNING)?;
+
+                // Anvil and ChunkData share the same YZX order
+                for (i, &index) in indices.iter().enumerate() {
+                    let block = blocks[index];
+                    if !block.is_air() {
+                        let i = i as u32;
+                        chunk_data.set_block(i % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE), (i / CHUNK_SIZE) % CHUNK_SIZE, block);
+                    }
+                }
+            }
+            // A single-entry palette has no data: the whole section is that block
+            _ => chunk_data = ChunkData::full_of_block(blocks[0]),
+        }
+
+        result.push((y, chunk_data));
+    }
+
+    Ok(result)
+}
+
+// Imports one .mca file into the chunk manager. Returns the number of sections loaded.
+pub fn import_region_file(path: &Path, mapping: &BlockMapping, chunk_manager: &mut ChunkManager) -> io::Result<usize> {
+    let bytes = fs::read(path)?;
+    let mut imported = 0;
+
+    for chunk in read_region(&bytes)? {
+        // Position is either at the root (1.18+) or in "Level"
+        let position = |key: &str| {
+            chunk
+                .get(key)
+                .or_else(|| chunk.get("Level").and_then(|level| level.get(key)))
+                .and_then(Tag::as_i64)
+                .ok_or_else(|| invalid("chunk without position"))
+        };
+        let (chunk_x, chunk_z) = (position("xPos")? as i32, position("zPos")? as i32);
+
+        for (chunk_y, chunk_data) in read_sections(&chunk, mapping)? {
+            chunk_manager.insert_chunk((chunk_x, chunk_y, chunk_z), chunk_data);
+            imported += 1;
+        }
+    }
+
+    Ok(imported)
+}
+
+// Imports every .mca file of a directory (e.g. a save's region/ folder)
+pub fn import_directory(dir: &Path, mapping: &BlockMapping, chunk_manager: &mut ChunkManager) -> io::Result<usize> {
+    let mut imported = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.extension().map_or(false, |ext| ext == "mca") {
+            match import_region_file(&path, mapping, chunk_manager) {
+                Ok(n) => imported += n,
+                Err(err) => println!("Skipping {}: {err}", path.display()),
+            }
+        }
+    }
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbt::write_nbt;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    const STONE: BlockID = BlockID(1);
+    const DIRT: BlockID = BlockID(2);
+    const COBBLESTONE: BlockID = BlockID(3);
+
+    fn compound(entries: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(entries.into_iter().map(|(name, tag)| (name.to_owned(), tag)).collect())
+    }
+
+    fn block_state(name: &str) -> Tag {
+        compound(vec![("Name", Tag::String(name.to_owned()))])
+    }
+
+    fn mapping(fallback: Option<BlockID>) -> BlockMapping {
+        let mut mapping = BlockMapping { names: HashMap::new(), fallback };
+        mapping.insert("minecraft:air", BlockID::AIR);
+        mapping.insert("minecraft:stone", STONE);
+        mapping.insert("minecraft:dirt", DIRT);
+        mapping
+    }
+
+    // Packs palette indices the way the given data version stores them
+    fn pack_indices(indices: &[usize], bits: usize, spanning: bool) -> Vec<i64> {
+        let len = if spanning { (indices.len() * bits).div_ceil(64) } else { indices.len().div_ceil(64 / bits) };
+        let mut words = vec![0u64; len];
+
+        for (i, &index) in indices.iter().enumerate() {
+            let index = index as u64;
+            if spanning {
+                let (word, shift) = (i * bits / 64, i * bits % 64);
+                words[word] |= index << shift;
+                if shift + bits > 64 {
+                    words[word + 1] |= index >> (64 - shift);
+                }
+            } else {
+                let per_word = 64 / bits;
+                words[i / per_word] |= index << (i % per_word * bits);
+            }
+        }
+
+        words.into_iter().map(|w| w as i64).collect()
+    }
+
+    // A 17 entry palette needs 5 bits, which don't divide 64: the two layouts differ
+    fn test_palette() -> (Vec<Tag>, Vec<usize>) {
+        let mut palette = vec![block_state("minecraft:air"), block_state("minecraft:stone"), block_state("minecraft:dirt")];
+        palette.extend((3..17).map(|i| block_state(&format!("minecraft:unknown_{i}"))));
+        let indices = (0..CHUNK_VOLUME as usize).map(|i| (i * 7 + i / 16) % 17).collect();
+
+        (palette, indices)
+    }
+
+    fn expected_block(index: usize, fallback: Option<BlockID>) -> BlockID {
+        match index {
+            0 => BlockID::AIR,
+            1 => STONE,
+            2 => DIRT,
+            _ => fallback.unwrap_or(BlockID::AIR),
+        }
+    }
+
+    fn assert_section(chunk: &ChunkData, indices: &[usize], fallback: Option<BlockID>) {
+        for (i, &index) in indices.iter().enumerate() {
+            let i = i as u32;
+            let block = chunk.get_block(i % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE), (i / CHUNK_SIZE) % CHUNK_SIZE);
+            assert_eq!(block, expected_block(index, fallback), "block {i}");
+        }
+    }
+
+    // 1.18+ layout: sections at the root, palette and data in block_states
+    fn modern_chunk(data_version: i64, spanning: bool) -> (Tag, Vec<usize>) {
+        let (palette, indices) = test_palette();
+        let section = compound(vec![
+            ("Y", Tag::Byte(-1)),
+            (
+                "block_states",
+                compound(vec![("palette", Tag::List(palette)), ("data", Tag::LongArray(pack_indices(&indices, 5, spanning)))]),
+            ),
+        ]);
+        let single = compound(vec![
+            ("Y", Tag::Byte(2)),
+            ("block_states", compound(vec![("palette", Tag::List(vec![block_state("minecraft:stone")]))])),
+        ]);
+        let only_air = compound(vec![
+            ("Y", Tag::Byte(3)),
+            ("block_states", compound(vec![("palette", Tag::List(vec![block_state("minecraft:air")]))])),
+        ]);
+        // Light only, no block states
+        let light = compound(vec![("Y", Tag::Byte(20))]);
+
+        let chunk = compound(vec![
+            ("DataVersion", Tag::Int(data_version as i32)),
+            ("xPos", Tag::Int(-3)),
+            ("zPos", Tag::Int(5)),
+            ("sections", Tag::List(vec![section, single, only_air, light])),
+        ]);
+
+        (chunk, indices)
+    }
+
+    #[test]
+    fn reads_modern_sections_without_spanning() {
+        let (chunk, indices) = modern_chunk(3465, false);
+        let sections = read_sections(&chunk, &mapping(Some(COBBLESTONE))).unwrap();
+
+        let ys: Vec<i32> = sections.iter().map(|(y, _)| *y).collect();
+        assert_eq!(ys, vec![-1, 2]);
+        assert_section(&sections[0].1, &indices, Some(COBBLESTONE));
+
+        // A single-entry palette without data is a section full of that block
+        for i in 0..CHUNK_VOLUME {
+            assert_eq!(sections[1].1.get_block(i % CHUNK_SIZE, i / 256, i / CHUNK_SIZE % CHUNK_SIZE), STONE);
+        }
+    }
+
+    #[test]
+    fn reads_spanning_indices_before_1_16() {
+        // Same layout keys, but an old data version: indices span two longs
+        let (chunk, indices) = modern_chunk(DATA_VERSION_NO_SPANNING - 1, true);
+        let sections = read_sections(&chunk, &mapping(None)).unwrap();
+
+        assert_section(&sections[0].1, &indices, None);
+    }
+
+    #[test]
+    fn reads_level_sections_before_1_18() {
+        let (palette, indices) = test_palette();
+        let section = compound(vec![
+            ("Y", Tag::Byte(4)),
+            ("Palette", Tag::List(palette)),
+            ("BlockStates", Tag::LongArray(pack_indices(&indices, 5, false))),
+        ]);
+        let chunk = compound(vec![
+            ("DataVersion", Tag::Int(2586)),
+            ("Level", compound(vec![("xPos", Tag::Int(0)), ("zPos", Tag::Int(0)), ("Sections", Tag::List(vec![section]))])),
+        ]);
+
+        let sections = read_sections(&chunk, &mapping(Some(COBBLESTONE))).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].0, 4);
+        assert_section(&sections[0].1, &indices, Some(COBBLESTONE));
+    }
+
+    #[test]
+    fn unknown_blocks_use_the_fallback() {
+        assert_eq!(mapping(Some(COBBLESTONE)).map("minecraft:unknown_3"), COBBLESTONE);
+        assert_eq!(mapping(None).map("minecraft:unknown_3"), BlockID::AIR);
+        assert_eq!(mapping(Some(COBBLESTONE)).map("minecraft:stone"), STONE);
+    }
+
+    #[test]
+    fn unpacks_both_layouts() {
+        let indices: Vec<usize> = (0..CHUNK_VOLUME as usize).map(|i| i % 5).collect();
+
+        // 4 bits divide 64, both layouts are the same
+        assert_eq!(unpack_indices(&pack_indices(&indices, 4, true), 5, true).unwrap(), indices);
+        assert_eq!(unpack_indices(&pack_indices(&indices, 4, false), 5, false).unwrap(), indices);
+
+        let indices: Vec<usize> = (0..CHUNK_VOLUME as usize).map(|i| i % 100).collect();
+        assert_eq!(unpack_indices(&pack_indices(&indices, 7, true), 100, true).unwrap(), indices);
+        assert_eq!(unpack_indices(&pack_indices(&indices, 7, false), 100, false).unwrap(), indices);
+    }
+
+    #[test]
+    fn rejects_bad_block_data() {
+        let indices: Vec<usize> = (0..CHUNK_VOLUME as usize).map(|i| i % 5).collect();
+        let data = pack_indices(&indices, 4, false);
+
+        assert!(unpack_indices(&data[..data.len() - 1], 5, false).is_err());
+        assert!(unpack_indices(&data[..data.len() - 1], 5, true).is_err());
+        // Index 4 with a palette of 4 entries
+        assert!(unpack_indices(&data, 4, false).is_err());
+    }
+
+    // An .mca file with one chunk per (entry index, compression, payload)
+    fn region_file(chunks: &[(usize, u8, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 2 * SECTOR_SIZE];
+
+        for (index, compression, payload) in chunks {
+            let sector = bytes.len() / SECTOR_SIZE;
+            let sectors = (payload.len() + 5).div_ceil(SECTOR_SIZE);
+            let entry = (sector as u32) << 8 | sectors as u32;
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&entry.to_be_bytes());
+
+            bytes.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+            bytes.push(*compression);
+            bytes.extend_from_slice(payload);
+            bytes.resize((sector + sectors) * SECTOR_SIZE, 0);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn reads_zlib_and_gzip_chunks() {
+        let (chunk, _) = modern_chunk(3465, false);
+        let nbt = write_nbt("", &chunk);
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&nbt).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&nbt).unwrap();
+
+        let bytes = region_file(&[(0, 2, zlib.finish().unwrap()), (37, 1, gzip.finish().unwrap()), (1023, 3, nbt)]);
+        let chunks = read_region(&bytes).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        for read in &chunks {
+            assert_eq!(read, &chunk);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_regions() {
+        assert!(read_region(&[0u8; SECTOR_SIZE]).is_err());
+
+        // Entry pointing past the end of the file
+        let mut bytes = vec![0u8; 2 * SECTOR_SIZE];
+        bytes[0..4].copy_from_slice(&(5u32 << 8 | 1).to_be_bytes());
+        assert!(read_region(&bytes).is_err());
+
+        // Unsupported compression, and external .mcc chunks
+        for compression in [9, 0x82] {
+            let bytes = region_file(&[(0, compression, vec![0u8; 10])]);
+            assert!(read_region(&bytes).is_err());
+        }
+
+        // Zlib payload that isn't zlib
+        let bytes = region_file(&[(0, 2, vec![1, 2, 3, 4])]);
+        assert!(read_region(&bytes).is_err());
+    }
+}
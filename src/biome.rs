@@ -0,0 +1,159 @@
+use noise::{NoiseFn, SuperSimplex};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Biome {
+    Plains,
+    Forest,
+    Desert,
+    Tundra,
+    Mountains,
+}
+
+// How a biome shapes the terrain: surface height = base + amplitude * noise
+#[derive(Copy, Clone, Debug)]
+pub struct TerrainShape {
+    pub base: f64,
+    pub amplitude: f64,
+}
+
+impl Biome {
+    pub const ALL: [Biome; 5] = [Biome::Plains, Biome::Forest, Biome::Desert, Biome::Tundra, Biome::Mountains];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Biome::Plains => "plains",
+            Biome::Forest => "forest",
+            Biome::Desert => "desert",
+            Biome::Tundra => "tundra",
+            Biome::Mountains => "mountains",
+        }
+    }
+
+    pub fn shape(self) -> TerrainShape {
+        let (base, amplitude) = match self {
+            Biome::Plains => (18.0, 4.0),
+            Biome::Forest => (20.0, 8.0),
+            Biome::Desert => (17.0, 3.0),
+            Biome::Tundra => (20.0, 6.0),
+            Biome::Mountains => (40.0, 24.0),
+        };
+
+        TerrainShape { base, amplitude }
+    }
+
+    // Registry names of the top block and of the few blocks under it
+    pub fn surface_blocks(self) -> (&'static str, &'static str) {
+        match self {
+            Biome::Plains | Biome::Forest => ("grass_block", "dirt"),
+            Biome::Desert => ("sand", "sand"),
+            Biome::Tundra => ("snowy_grass_block", "dirt"),
+            Biome::Mountains => ("stone", "stone"),
+        }
+    }
+
+    // Chance for each column to grow a tree
+    pub fn tree_density(self) -> f32 {
+        match self {
+            Biome::Plains => 0.005,
+            Biome::Forest => 0.04,
+            Biome::Desert => 0.0,
+            Biome::Tundra => 0.003,
+            Biome::Mountains => 0.002,
+        }
+    }
+
+    // Chance for each column without a tree to have a rock
+    pub fn rock_density(self) -> f32 {
+        match self {
+            Biome::Plains => 0.001,
+            Biome::Forest => 0.0005,
+            Biome::Desert => 0.0,
+            Biome::Tundra => 0.002,
+            Biome::Mountains => 0.006,
+        }
+    }
+
+    // Chance for each column to grow a plant on its grass
+    pub fn plant_density(self) -> f32 {
+        match self {
+            Biome::Plains => 0.1,
+            Biome::Forest => 0.15,
+            Biome::Desert | Biome::Mountains => 0.0,
+            Biome::Tundra => 0.02,
+        }
+    }
+
+    // Registry names of the plants picked from, repeated to make them more common
+    pub fn plants(self) -> &'static [&'static str] {
+        match self {
+            Biome::Plains => &["tall_grass", "tall_grass", "tall_grass", "tall_grass", "dandelion", "poppy"],
+            Biome::Forest => &["tall_grass", "tall_grass", "poppy", "oak_sapling", "red_mushroom", "brown_mushroom"],
+            Biome::Tundra => &["tall_grass"],
+            Biome::Desert | Biome::Mountains => &[],
+        }
+    }
+
+    pub fn has_ruins(self) -> bool {
+        matches!(self, Biome::Plains | Biome::Desert)
+    }
+
+    // Temperature and humidity are both in -1..=1
+    pub fn from_climate(temperature: f64, humidity: f64) -> Biome {
+        if temperature < -0.4 {
+            Biome::Tundra
+        } else if temperature > 0.35 && humidity < -0.1 {
+            Biome::Desert
+        } else if humidity > 0.3 {
+            Biome::Forest
+        } else if temperature < -0.1 && humidity < -0.2 {
+            Biome::Mountains
+        } else {
+            Biome::Plains
+        }
+    }
+}
+
+// Distance between the samples averaged to blend biome edges, and how many on each side
+const BLEND_SPACING: i32 = 4;
+const BLEND_RADIUS: i32 = 2;
+
+// Two low frequency noise fields picking the biome of every column
+pub struct Climate {
+    temperature: SuperSimplex,
+    humidity: SuperSimplex,
+}
+
+impl Climate {
+    pub fn new(seed: u32) -> Climate {
+        Climate {
+            temperature: SuperSimplex::new(seed.wrapping_add(1)),
+            humidity: SuperSimplex::new(seed.wrapping_add(2)),
+        }
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        let (xf, zf) = (x as f64 / 512.0, z as f64 / 512.0);
+
+        Biome::from_climate(self.temperature.get([xf, zf]), self.humidity.get([xf, zf]))
+    }
+
+    // Terrain shape averaged over the biomes around the column, so heights don't jump at biome edges
+    pub fn blended_shape(&self, x: i32, z: i32) -> TerrainShape {
+        let mut blended = TerrainShape { base: 0.0, amplitude: 0.0 };
+        let mut samples = 0.0;
+
+        for dz in -BLEND_RADIUS..=BLEND_RADIUS {
+            for dx in -BLEND_RADIUS..=BLEND_RADIUS {
+                let shape = self.biome_at(x + dx * BLEND_SPACING, z + dz * BLEND_SPACING).shape();
+
+                blended.base += shape.base;
+                blended.amplitude += shape.amplitude;
+                samples += 1.0;
+            }
+        }
+
+        blended.base /= samples;
+        blended.amplitude /= samples;
+        blended
+    }
+}
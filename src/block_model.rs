@@ -0,0 +1,194 @@
+use crate::shapes::FACE_CORNERS;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Block models for blocks that aren't full cubes, loaded from `model = "models/x.json"` in
+// blocks.toml. Close to Minecraft's format: a list of boxes in 16ths of a block, e.g.
+//
+// { "elements": [{
+//     "from": [0, 0, 0], "to": [16, 8, 16],
+//     "rotation": { "origin": [8, 8, 8], "axis": "y", "angle": 45 },
+//     "faces": { "up": { "uv": [0, 0, 16, 16], "texture": "up", "cullface": "up" }, ... }
+// }] }
+//
+// Faces are named by direction: east (+x), west (-x), up, down, south (+z), north (-z). `uv` is in
+// pixels of the tile, top left first, and defaults to the part of the tile the face covers.
+// `texture` picks which of the block's face textures to use, the face's own by default. A face
+// with a `cullface` isn't drawn when the neighbour on that side covers it.
+
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    East,
+    West,
+    Up,
+    Down,
+    South,
+    North,
+}
+
+impl Direction {
+    // In the order of Sides
+    fn side(self) -> usize {
+        match self {
+            Direction::East => 0,
+            Direction::West => 1,
+            Direction::Up => 2,
+            Direction::Down => 3,
+            Direction::South => 4,
+            Direction::North => 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Deserialize, Debug)]
+struct Rotation {
+    origin: [f32; 3],
+    axis: Axis,
+    // Degrees
+    angle: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Face {
+    #[serde(default)]
+    uv: Option<[f32; 4]>,
+    #[serde(default)]
+    texture: Option<Direction>,
+    #[serde(default)]
+    cullface: Option<Direction>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Element {
+    from: [f32; 3],
+    to: [f32; 3],
+    #[serde(default)]
+    rotation: Option<Rotation>,
+    faces: HashMap<Direction, Face>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelFile {
+    elements: Vec<Element>,
+}
+
+// One face of a model, ready to be offset to the block's position
+#[derive(Clone, Debug)]
+pub struct ModelQuad {
+    // In blocks from the block origin, in the corner order of FACE_CORNERS
+    pub corners: [[f32; 3]; 4],
+    // In tiles, v going up like the atlas
+    pub uvs: [[f32; 2]; 4],
+    // Face of the block whose texture is used
+    pub texture: usize,
+    pub cullface: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockModel {
+    pub quads: Vec<ModelQuad>,
+    // Sides entirely covered by the model, which hide the faces of the neighbours against them
+    full_faces: [bool; 6],
+}
+
+// uv of the part of the tile the face of a box covers, in pixels from the top left
+fn default_uv(side: usize, from: [f32; 3], to: [f32; 3]) -> [f32; 4] {
+    match side {
+        0 => [16.0 - to[2], 16.0 - to[1], 16.0 - from[2], 16.0 - from[1]],
+        1 => [from[2], 16.0 - to[1], to[2], 16.0 - from[1]],
+        2 => [from[0], from[2], to[0], to[2]],
+        3 => [from[0], 16.0 - to[2], to[0], 16.0 - from[2]],
+        4 => [from[0], 16.0 - to[1], to[0], 16.0 - from[1]],
+        _ => [16.0 - to[0], 16.0 - to[1], 16.0 - from[0], 16.0 - from[1]],
+    }
+}
+
+fn rotate(point: [f32; 3], rotation: &Rotation) -> [f32; 3] {
+    let (sin, cos) = rotation.angle.to_radians().sin_cos();
+    let [x, y, z] = [0, 1, 2].map(|i| point[i] - rotation.origin[i]);
+
+    let [x, y, z] = match rotation.axis {
+        Axis::X => [x, y * cos - z * sin, y * sin + z * cos],
+        Axis::Y => [x * cos + z * sin, y, -x * sin + z * cos],
+        Axis::Z => [x * cos - y * sin, x * sin + y * cos, z],
+    };
+    [x + rotation.origin[0], y + rotation.origin[1], z + rotation.origin[2]]
+}
+
+impl BlockModel {
+    pub fn from_json(source: &str) -> Result<BlockModel, String> {
+        let file: ModelFile = serde_json::from_str(source).map_err(|err| err.to_string())?;
+
+        let mut quads = Vec::new();
+        // Pixels of each side covered by unrotated boxes touching it
+        let mut covered = [[false; 256]; 6];
+
+        for element in &file.elements {
+            let (from, to) = (element.from, element.to);
+            let rotated = element.rotation.as_ref().filter(|rotation| rotation.angle != 0.0);
+
+            for (direction, face) in &element.faces {
+                let side = direction.side();
+
+                let corners = FACE_CORNERS[side].map(|(cx, cy, cz)| {
+                    let corner = [(cx, 0), (cy, 1), (cz, 2)].map(|(c, i)| if c == 1 { to[i] } else { from[i] });
+                    let corner = rotated.map_or(corner, |rotation| rotate(corner, rotation));
+                    corner.map(|v| v / 16.0)
+                });
+
+                // Same corner order as the cube faces: (0, 0), (1, 0), (1, 1), (0, 1) in the tile
+                let [u0, v0, u1, v1] = face.uv.unwrap_or_else(|| default_uv(side, from, to));
+                let uvs = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+                    .map(|(u, v): (f32, f32)| [(u0 + (u1 - u0) * u) / 16.0, (16.0 - (v1 + (v0 - v1) * v)) / 16.0]);
+
+                quads.push(ModelQuad {
+                    corners,
+                    uvs,
+                    texture: face.texture.map_or(side, Direction::side),
+                    cullface: face.cullface.map(Direction::side),
+                });
+
+                let normal_axis = side / 2;
+                let on_border = if side % 2 == 0 { to[normal_axis] >= 16.0 } else { from[normal_axis] <= 0.0 };
+
+                if rotated.is_none() && on_border {
+                    let (a_axis, b_axis) = ((normal_axis + 1) % 3, (normal_axis + 2) % 3);
+                    let range = |axis: usize| from[axis].max(0.0).round() as usize..to[axis].min(16.0).round() as usize;
+
+                    for b in range(b_axis) {
+                        for a in range(a_axis) {
+                            covered[side][b * 16 + a] = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(BlockModel {
+            quads,
+            full_faces: covered.map(|pixels| pixels.iter().all(|&pixel| pixel)),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<BlockModel, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("Filename: {path}, error: {err}"))?;
+        BlockModel::from_json(&source).map_err(|err| format!("Filename: {path}, error: {err}"))
+    }
+
+    pub fn is_full(&self, side: usize) -> bool {
+        self.full_faces[side]
+    }
+
+    pub fn has_full_face(&self) -> bool {
+        self.full_faces.iter().any(|&full| full)
+    }
+}
@@ -0,0 +1,185 @@
+use crate::block_model::BlockModel;
+use crate::block_texture_sides::BlockFaces;
+use crate::chunk::BlockID;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+fn default_true() -> bool {
+    true
+}
+
+// One [[block]] entry of blocks.toml
+#[derive(Deserialize, Clone, Debug)]
+pub struct BlockDefinition {
+    pub id: u16,
+    pub name: String,
+    // Air and other invisible blocks have no textures
+    #[serde(default)]
+    pub textures: Option<BlockFaces<String>>,
+    // Neighbouring faces are still drawn (leaves, glass...)
+    #[serde(default)]
+    pub transparent: bool,
+    // Blended with what's behind it in a separate pass (ice...). Has to be transparent too.
+    #[serde(default)]
+    pub translucent: bool,
+    // The player collides with it
+    #[serde(default = "default_true")]
+    pub solid: bool,
+    // Negative hardness: cannot be broken
+    #[serde(default)]
+    pub hardness: f32,
+    // 0..=15
+    #[serde(default)]
+    pub light_emission: u8,
+    // Path of a JSON block model (slabs, torches...), see block_model. Full cube without one.
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BlockFile {
+    block: Vec<BlockDefinition>,
+}
+
+pub struct BlockRegistry {
+    // Indexed by BlockID
+    blocks: Vec<Option<BlockDefinition>>,
+    by_name: HashMap<String, BlockID>,
+    // Of the blocks with a model, filled by load
+    models: HashMap<BlockID, BlockModel>,
+}
+
+impl BlockRegistry {
+    fn air() -> BlockDefinition {
+        BlockDefinition {
+            id: BlockID::AIR.0,
+            name: "air".to_owned(),
+            textures: None,
+            transparent: true,
+            translucent: false,
+            solid: false,
+            hardness: 0.0,
+            light_emission: 0,
+            model: None,
+        }
+    }
+
+    pub fn from_definitions(definitions: Vec<BlockDefinition>) -> Result<BlockRegistry, String> {
+        let mut registry = BlockRegistry {
+            blocks: Vec::new(),
+            by_name: HashMap::new(),
+            models: HashMap::new(),
+        };
+
+        registry.register(BlockRegistry::air())?;
+
+        for definition in definitions {
+            registry.register(definition)?;
+        }
+
+        Ok(registry)
+    }
+
+    pub fn from_toml(source: &str) -> Result<BlockRegistry, String> {
+        let file: BlockFile = toml::from_str(source).map_err(|err| err.to_string())?;
+        BlockRegistry::from_definitions(file.block)
+    }
+
+    pub fn load(path: &str) -> Result<BlockRegistry, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("Filename: {path}, error: {err}"))?;
+        let mut registry = BlockRegistry::from_toml(&source).map_err(|err| format!("Filename: {path}, error: {err}"))?;
+
+        let models: Vec<(BlockID, String)> = registry
+            .iter()
+            .filter_map(|(block, definition)| definition.model.clone().map(|model| (block, model)))
+            .collect();
+
+        for (block, model) in models {
+            registry.models.insert(block, BlockModel::load(&model)?);
+        }
+
+        Ok(registry)
+    }
+
+    fn register(&mut self, definition: BlockDefinition) -> Result<(), String> {
+        let id = definition.id as usize;
+
+        if self.blocks.get(id).map_or(false, |b| b.is_some()) {
+            return Err(format!("Block id {} is used twice ('{}')", id, definition.name));
+        }
+        if self.by_name.contains_key(&definition.name) {
+            return Err(format!("Block name '{}' is used twice", definition.name));
+        }
+        if definition.translucent && !definition.transparent {
+            return Err(format!("Block '{}' is translucent but not transparent", definition.name));
+        }
+        // Models are lit from their own cell, which only gets light when the block is transparent
+        if definition.model.is_some() && !definition.transparent {
+            return Err(format!("Block '{}' has a model but is not transparent", definition.name));
+        }
+        if definition.model.is_some() && definition.textures.is_none() {
+            return Err(format!("Block '{}' has a model but no textures", definition.name));
+        }
+        if definition.light_emission > 15 {
+            return Err(format!("Block '{}' emits more than 15 light", definition.name));
+        }
+
+        if self.blocks.len() <= id {
+            self.blocks.resize(id + 1, None);
+        }
+
+        self.by_name.insert(definition.name.clone(), BlockID(definition.id));
+        self.blocks[id] = Some(definition);
+
+        Ok(())
+    }
+
+    // Unknown ids (e.g. from a newer definitions file) fall back to air
+    #[inline]
+    pub fn get(&self, block: BlockID) -> &BlockDefinition {
+        self.blocks
+            .get(block.0 as usize)
+            .and_then(|b| b.as_ref())
+            .unwrap_or_else(|| self.blocks[0].as_ref().unwrap())
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<BlockID> {
+        self.by_name.get(name).copied()
+    }
+
+    #[inline]
+    pub fn is_transparent(&self, block: BlockID) -> bool {
+        self.get(block).transparent
+    }
+
+    #[inline]
+    pub fn is_translucent(&self, block: BlockID) -> bool {
+        self.get(block).translucent
+    }
+
+    pub fn model(&self, block: BlockID) -> Option<&BlockModel> {
+        self.models.get(&block)
+    }
+
+    // Whether the block covers its whole face on `side`, so the neighbour's face against it can't be
+    // seen. Only full faces of models count, transparent cubes like leaves cover nothing.
+    #[inline]
+    pub fn hides_face(&self, block: BlockID, side: usize) -> bool {
+        match self.models.get(&block) {
+            Some(model) => model.is_full(side),
+            None => !self.is_transparent(block),
+        }
+    }
+
+    #[inline]
+    pub fn is_solid(&self, block: BlockID) -> bool {
+        self.get(block).solid
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BlockID, &BlockDefinition)> {
+        self.blocks
+            .iter()
+            .filter_map(|b| b.as_ref())
+            .map(|b| (BlockID(b.id), b))
+    }
+}
@@ -0,0 +1,355 @@
+use crate::chunk::BlockID;
+use crate::chunk_manager::CHUNK_VOLUME;
+use std::io::{self, Read};
+
+// Palette-compressed block storage for one chunk.
+// A chunk made of one block is a single value; otherwise each block is an index into
+// the palette, packed into u64 words with as few bits as the palette needs.
+#[derive(Clone)]
+pub enum BlockStorage {
+    Single(BlockID),
+    Paletted {
+        palette: Vec<BlockID>,
+        // How many blocks use each palette entry. An entry with 0 is a free slot.
+        counts: Vec<u16>,
+        bits_per_block: u32,
+        data: Vec<u64>,
+    },
+}
+
+impl BlockStorage {
+    pub fn new(block: BlockID) -> BlockStorage {
+        BlockStorage::Single(block)
+    }
+
+    #[inline]
+    fn blocks_per_word(bits_per_block: u32) -> usize {
+        (64 / bits_per_block) as usize
+    }
+
+    // Entries never span two words, so a word holds 64 / bits entries.
+    fn words_needed(bits_per_block: u32) -> usize {
+        let per_word = BlockStorage::blocks_per_word(bits_per_block);
+        (CHUNK_VOLUME as usize + per_word - 1) / per_word
+    }
+
+    #[inline]
+    fn read_index(data: &[u64], bits_per_block: u32, index: usize) -> usize {
+        let per_word = BlockStorage::blocks_per_word(bits_per_block);
+        let shift = (index % per_word) as u32 * bits_per_block;
+        let mask = (1u64 << bits_per_block) - 1;
+
+        ((data[index / per_word] >> shift) & mask) as usize
+    }
+
+    #[inline]
+    fn write_index(data: &mut [u64], bits_per_block: u32, index: usize, value: usize) {
+        let per_word = BlockStorage::blocks_per_word(bits_per_block);
+        let shift = (index % per_word) as u32 * bits_per_block;
+        let mask = (1u64 << bits_per_block) - 1;
+        let word = &mut data[index / per_word];
+
+        *word = (*word & !(mask << shift)) | ((value as u64 & mask) << shift);
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> BlockID {
+        match self {
+            BlockStorage::Single(block) => *block,
+            BlockStorage::Paletted { palette, bits_per_block, data, .. } => {
+                palette[BlockStorage::read_index(data, *bits_per_block, index)]
+            }
+        }
+    }
+
+    pub fn set(&mut self, index: usize, block: BlockID) {
+        if let BlockStorage::Single(current) = *self {
+            if current == block {
+                return;
+            }
+
+            // Split the single value into a 1-bit palette: every block points at entry 0
+            *self = BlockStorage::Paletted {
+                palette: vec![current, block],
+                counts: vec![CHUNK_VOLUME as u16, 0],
+                bits_per_block: 1,
+                data: vec![0; BlockStorage::words_needed(1)],
+            };
+        }
+
+        let collapse = match self {
+            BlockStorage::Single(_) => unreachable!(),
+            BlockStorage::Paletted { palette, counts, bits_per_block, data } => {
+                let old = BlockStorage::read_index(data, *bits_per_block, index);
+
+                if palette[old] == block && counts[old] > 0 {
+                    return;
+                }
+
+                let new = match palette.iter().zip(counts.iter()).position(|(&b, &c)| b == block && c > 0) {
+                    Some(i) => i,
+                    None => match counts.iter().position(|&c| c == 0) {
+                        // Reuse a slot nobody points at anymore
+                        Some(i) => {
+                            palette[i] = block;
+                            i
+                        }
+                        None => {
+                            palette.push(block);
+                            counts.push(0);
+
+                            if palette.len() > 1 << *bits_per_block {
+                                BlockStorage::grow(bits_per_block, data);
+                            }
+
+                            palette.len() - 1
+                        }
+                    },
+                };
+
+                counts[old] -= 1;
+                counts[new] += 1;
+                BlockStorage::write_index(data, *bits_per_block, index, new);
+
+                if counts[new] as u32 == CHUNK_VOLUME {
+                    Some(block)
+                } else {
+                    None
+                }
+            }
+        };
+
+        // Everything is the same block again
+        if let Some(block) = collapse {
+            *self = BlockStorage::Single(block);
+        }
+    }
+
+    // Repack every index with one more bit per block
+    fn grow(bits_per_block: &mut u32, data: &mut Vec<u64>) {
+        let new_bits = *bits_per_block + 1;
+        let mut new_data = vec![0; BlockStorage::words_needed(new_bits)];
+
+        for i in 0..CHUNK_VOLUME as usize {
+            let value = BlockStorage::read_index(data, *bits_per_block, i);
+            BlockStorage::write_index(&mut new_data, new_bits, i, value);
+        }
+
+        *bits_per_block = new_bits;
+        *data = new_data;
+    }
+
+    // Binary layout used by region files (little endian):
+    // Single:   0u8, id: u16
+    // Paletted: 1u8, bits: u8, palette_len: u16, ids: [u16], words: [u64]
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BlockStorage::Single(block) => {
+                out.push(0);
+                out.extend_from_slice(&block.0.to_le_bytes());
+            }
+            BlockStorage::Paletted { palette, bits_per_block, data, .. } => {
+                out.push(1);
+                out.push(*bits_per_block as u8);
+                out.extend_from_slice(&(palette.len() as u16).to_le_bytes());
+
+                for block in palette {
+                    out.extend_from_slice(&block.0.to_le_bytes());
+                }
+                for word in data {
+                    out.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    pub fn read_from(input: &mut impl Read) -> io::Result<BlockStorage> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+        let mut u8_buf = [0u8; 1];
+        let mut u16_buf = [0u8; 2];
+        let mut u64_buf = [0u8; 8];
+
+        input.read_exact(&mut u8_buf)?;
+        match u8_buf[0] {
+            0 => {
+                input.read_exact(&mut u16_buf)?;
+                Ok(BlockStorage::Single(BlockID(u16::from_le_bytes(u16_buf))))
+            }
+            1 => {
+                input.read_exact(&mut u8_buf)?;
+                let bits_per_block = u8_buf[0] as u32;
+                input.read_exact(&mut u16_buf)?;
+                let palette_len = u16::from_le_bytes(u16_buf) as usize;
+
+                if bits_per_block == 0 || bits_per_block > 16 || palette_len > 1 << bits_per_block {
+                    return Err(invalid("bad palette size"));
+                }
+
+                let mut palette = Vec::with_capacity(palette_len);
+                for _ in 0..palette_len {
+                    input.read_exact(&mut u16_buf)?;
+                    palette.push(BlockID(u16::from_le_bytes(u16_buf)));
+                }
+
+                let mut data = Vec::with_capacity(BlockStorage::words_needed(bits_per_block));
+                for _ in 0..BlockStorage::words_needed(bits_per_block) {
+                    input.read_exact(&mut u64_buf)?;
+                    data.push(u64::from_le_bytes(u64_buf));
+                }
+
+                // Reference counts are not stored, rebuild them
+                let mut counts = vec![0u16; palette_len];
+                for i in 0..CHUNK_VOLUME as usize {
+                    let index = BlockStorage::read_index(&data, bits_per_block, i);
+                    match counts.get_mut(index) {
+                        Some(count) => *count += 1,
+                        None => return Err(invalid("palette index out of range")),
+                    }
+                }
+
+                Ok(BlockStorage::Paletted { palette, counts, bits_per_block, data })
+            }
+            _ => Err(invalid("unknown block storage kind")),
+        }
+    }
+
+    // Bytes used by this storage, including the heap-allocated palette and words.
+    pub fn memory_usage(&self) -> usize {
+        let heap = match self {
+            BlockStorage::Single(_) => 0,
+            BlockStorage::Paletted { palette, counts, data, .. } => {
+                palette.capacity() * std::mem::size_of::<BlockID>()
+                    + counts.capacity() * std::mem::size_of::<u16>()
+                    + data.capacity() * std::mem::size_of::<u64>()
+            }
+        };
+
+        std::mem::size_of::<BlockStorage>() + heap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const FLAT_BYTES: usize = CHUNK_VOLUME as usize * std::mem::size_of::<BlockID>();
+
+    fn bits_per_block(storage: &BlockStorage) -> Option<u32> {
+        match storage {
+            BlockStorage::Single(_) => None,
+            BlockStorage::Paletted { bits_per_block, .. } => Some(*bits_per_block),
+        }
+    }
+
+    // Random writes of up to `kinds` different blocks, checked against a plain array
+    fn random_writes(kinds: u16, writes: usize, seed: u64) -> (BlockStorage, Vec<BlockID>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut storage = BlockStorage::new(BlockID::AIR);
+        let mut flat = vec![BlockID::AIR; CHUNK_VOLUME as usize];
+
+        for _ in 0..writes {
+            let (index, block) = (rng.gen_range(0..CHUNK_VOLUME as usize), BlockID(rng.gen_range(0..kinds)));
+            storage.set(index, block);
+            flat[index] = block;
+            assert_eq!(storage.get(index), block);
+        }
+
+        for (index, &block) in flat.iter().enumerate() {
+            assert_eq!(storage.get(index), block, "block {index} differs");
+        }
+
+        (storage, flat)
+    }
+
+    #[test]
+    fn matches_flat_array_under_random_writes() {
+        for (kinds, seed) in [(2, 1), (5, 2), (40, 3), (300, 4)] {
+            random_writes(kinds, 20_000, seed);
+        }
+    }
+
+    #[test]
+    fn palette_grows_with_block_kinds() {
+        let mut storage = BlockStorage::new(BlockID::AIR);
+
+        for i in 1..=20u16 {
+            storage.set(i as usize, BlockID(i));
+        }
+
+        // 21 kinds need 5 bits, and every block is still where it was put
+        assert_eq!(bits_per_block(&storage), Some(5));
+        assert_eq!(storage.get(0), BlockID::AIR);
+        for i in 1..=20u16 {
+            assert_eq!(storage.get(i as usize), BlockID(i));
+        }
+    }
+
+    #[test]
+    fn collapses_back_to_single() {
+        let (mut storage, _) = random_writes(10, 5000, 5);
+        assert!(bits_per_block(&storage).is_some());
+
+        let stone = BlockID(1);
+        for index in 0..CHUNK_VOLUME as usize {
+            storage.set(index, stone);
+        }
+
+        assert!(matches!(storage, BlockStorage::Single(block) if block == stone));
+    }
+
+    #[test]
+    fn reuses_free_palette_slots() {
+        let mut storage = BlockStorage::new(BlockID::AIR);
+
+        // Each new block replaces the only block of the previous one. Its slot is freed after the
+        // new block got one, so three slots are enough.
+        for i in 1..100u16 {
+            storage.set(0, BlockID(i));
+        }
+
+        assert_eq!(bits_per_block(&storage), Some(2));
+        assert_eq!(storage.get(0), BlockID(99));
+    }
+
+    #[test]
+    fn uses_less_memory_than_flat_array() {
+        let single = BlockStorage::new(BlockID::AIR);
+        assert!(single.memory_usage() < FLAT_BYTES / 100);
+
+        // Up to 8 bits per block, a quarter of the bits of a u16 array or better
+        for kinds in [2, 4, 16, 256] {
+            let (storage, _) = random_writes(kinds, 50_000, kinds as u64);
+            let expected = FLAT_BYTES * bits_per_block(&storage).unwrap() as usize / 16;
+
+            assert!(storage.memory_usage() < FLAT_BYTES, "{kinds} kinds use {} bytes", storage.memory_usage());
+            assert!(storage.memory_usage() < expected + FLAT_BYTES / 4, "{kinds} kinds use {} bytes", storage.memory_usage());
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        for (kinds, seed) in [(1, 6), (3, 7), (100, 8)] {
+            let (storage, flat) = random_writes(kinds, 10_000, seed);
+
+            let mut bytes = Vec::new();
+            storage.write_to(&mut bytes);
+            let read = BlockStorage::read_from(&mut bytes.as_slice()).unwrap();
+
+            for (index, &block) in flat.iter().enumerate() {
+                assert_eq!(read.get(index), block);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_bytes() {
+        assert!(BlockStorage::read_from(&mut [2u8, 0, 0].as_slice()).is_err());
+        // 3 entries don't fit in 1 bit
+        assert!(BlockStorage::read_from(&mut [1u8, 1, 3, 0].as_slice()).is_err());
+        // Cut off in the middle of the words
+        assert!(BlockStorage::read_from(&mut [1u8, 1, 2, 0, 0, 0, 1, 0, 0xff].as_slice()).is_err());
+    }
+}
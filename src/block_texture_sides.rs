@@ -1,6 +1,10 @@
 use crate::{UVCoords, UVFaces};
+use serde::Deserialize;
 
-#[derive(Copy, Clone)]
+// In blocks.toml: `textures = "a.png"`, `textures = { sides = .., top = .., bottom = .. }`
+// or a table with all six faces.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(untagged)]
 pub enum BlockFaces<T> {
     All(T),
     Sides {sides: T, top: T, bottom: T},
@@ -1,142 +1,111 @@
 // use crate::{debugging, shapes::unit_cube_array};
+use crate::block_storage::BlockStorage;
 use crate::chunk_manager::{CHUNK_SIZE, CHUNK_VOLUME};
-use rand::prelude::Distribution;
-use rand::distributions::Standard;
-use rand::random;
+use crate::light::{LightKind, LightMap};
+use rand::seq::SliceRandom;
 use std::collections::HashSet;
-// use std::os::raw::c_void;
-use crate::gl_call;
+use std::io::{self, Read};
 
-
-
-fn create_vao_vbo() -> (u32, u32) {
-    let mut vao = 0;
-    gl_call!(gl::CreateVertexArrays(1, &mut vao));
-
-    // Position
-    gl_call!(gl::EnableVertexArrayAttrib(vao, 0));
-    gl_call!(gl::VertexArrayAttribFormat(vao, 0, 3_i32, gl::FLOAT, gl::FALSE, 0));
-    gl_call!(gl::VertexArrayAttribBinding(vao, 0, 0));
-
-    // Texture coords
-    gl_call!(gl::EnableVertexArrayAttrib(vao, 1));
-    gl_call!(gl::VertexArrayAttribFormat(vao, 1, 2_i32, gl::FLOAT, gl::FALSE, (3 * std::mem::size_of::<f32>()) as u32));
-    gl_call!(gl::VertexArrayAttribBinding(vao, 1, 0));
-
-    let mut vbo = 0;
-    gl_call!(gl::CreateBuffers(1, &mut vbo));
-    // gl_call!(gl::NamedBufferData(vbo, (180 * CHUNK_VOLUME as usize * std::mem::size_of::<f32>()) as isize, std::ptr::null(), gl::DYNAMIC_DRAW));
-    
-    gl_call!(gl::VertexArrayVertexBuffer(vao, 0, vbo, 0, (5 * std::mem::size_of::<f32>()) as i32));
-
-    (vao, vbo)
-}
-
-#[derive(Copy, Clone, Eq, PartialEq, Hash)]
-pub enum BlockID {
-    Air,
-    Dirt,
-    GrassBlock,
-    Cobblestone,
-    Obsidian,
-    OakLog,
-    OakLeaves,
-    Debug,
-    Debug2,
-}
+// Numeric id from blocks.toml. Everything else about a block lives in the BlockRegistry.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct BlockID(pub u16);
 
 impl BlockID {
-    pub fn is_air(&self) -> bool {
-        self == &BlockID::Air
-    }
+    pub const AIR: BlockID = BlockID(0);
 
-    pub fn is_transparent(&self) -> bool {
-        match self {
-            BlockID::Air | BlockID::OakLeaves => true,
-            _ => false,
-        }
-    }
-}
-
-impl Distribution<BlockID> for Standard {
-    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> BlockID {
-        match rng.gen_range(1..4) {
-            // 0 => BlockID::AIR,
-            1 => BlockID::Dirt,
-            2 => BlockID::Cobblestone,
-            3 => BlockID::Obsidian,
-            _ => BlockID::Air,
-        }
+    pub fn is_air(&self) -> bool {
+        self == &BlockID::AIR
     }
 }
 
-pub struct Chunk {
-    blocks: [BlockID; CHUNK_VOLUME as usize],
-    pub vao: u32,
-    pub vbo: u32,
-    pub vertices_drawn: u32, // for information.
+// Pure voxel data. GPU buffers live in ChunkManager::meshes so that
+// chunks can be created and edited without an OpenGL context.
+#[derive(Clone)]
+pub struct ChunkData {
+    blocks: BlockStorage,
+    light: LightMap,
     pub dirty: bool, // data is changed: needs reloading
     pub dirty_neighbours: HashSet<(i32, i32, i32)>,
+    pub modified: bool, // changed since generated or loaded: needs saving
 }
 
-impl Chunk {
+impl ChunkData {
 
+    // Including the diagonal ones, whose ambient occlusion depends on the blocks at our edges
     fn all_neighbours() -> HashSet<(i32, i32, i32)> {
         let mut hash_set = HashSet::new();
 
-        hash_set.insert((1, 0, 0));
-        hash_set.insert((0, 1, 0));
-        hash_set.insert((0, 0, 1));
-        hash_set.insert((-1, 0, 0));
-        hash_set.insert((0, -1, 0));
-        hash_set.insert((0, 0, -1));
-        
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    if (x, y, z) != (0, 0, 0) {
+                        hash_set.insert((x, y, z));
+                    }
+                }
+            }
+        }
+
         hash_set
     }
 
-    pub fn empty() -> Chunk {
-        let (vao, vbo) = create_vao_vbo();
-        Chunk {
-            blocks: [BlockID::Air; CHUNK_VOLUME as usize],
-            vao,
-            vbo,
-            vertices_drawn: 0,
+    pub fn empty() -> ChunkData {
+        ChunkData {
+            blocks: BlockStorage::new(BlockID::AIR),
+            light: LightMap::new(),
             dirty: false,
-            dirty_neighbours: Chunk::all_neighbours(),
+            dirty_neighbours: ChunkData::all_neighbours(),
+            modified: false,
         }
     }
 
-    pub fn full_of_block(block: BlockID) -> Chunk {
-        let (vao, vbo) = create_vao_vbo();
-        Chunk {
-            blocks: [block; CHUNK_VOLUME as usize],
-            vao,
-            vbo,
-            vertices_drawn: 0,
+    pub fn full_of_block(block: BlockID) -> ChunkData {
+        ChunkData {
+            blocks: BlockStorage::new(block),
+            light: LightMap::new(),
             dirty: true,
-            dirty_neighbours: Chunk::all_neighbours(),
+            dirty_neighbours: ChunkData::all_neighbours(),
+            modified: false,
         }
     }
 
-    pub fn random() -> Chunk {
-        let (vao, vbo) = create_vao_vbo();
-
-        let mut chunk = Chunk {
-            blocks: [BlockID::Air; CHUNK_VOLUME as usize],
-            vao,
-            vbo,
-            vertices_drawn: 0,
+    pub fn random(choices: &[BlockID]) -> ChunkData {
+        let mut rng = rand::thread_rng();
+        let mut chunk = ChunkData {
+            blocks: BlockStorage::new(BlockID::AIR),
+            light: LightMap::new(),
             dirty: true,
-            dirty_neighbours: Chunk::all_neighbours(),
+            dirty_neighbours: ChunkData::all_neighbours(),
+            modified: false,
         };
 
-        for i in 0..chunk.blocks.len() {
-            chunk.blocks[i] = random::<BlockID>();
+        for i in 0..CHUNK_VOLUME as usize {
+            chunk.blocks.set(i, *choices.choose(&mut rng).unwrap_or(&BlockID::AIR));
         }
 
         chunk
     }
 
+    // Loaded chunks need meshing, and so do their neighbours
+    pub fn read_from(input: &mut impl Read) -> io::Result<ChunkData> {
+        Ok(ChunkData {
+            blocks: BlockStorage::read_from(input)?,
+            light: LightMap::new(),
+            dirty: true,
+            dirty_neighbours: ChunkData::all_neighbours(),
+            modified: false,
+        })
+    }
+
+    // Put back after being unloaded: it needs meshing, and so do its neighbours
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = true;
+        self.dirty_neighbours = ChunkData::all_neighbours();
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        self.blocks.write_to(out);
+    }
+
     #[inline]
     fn coords_to_index(x: u32, y: u32, z: u32) -> usize {
         (y * (CHUNK_SIZE * CHUNK_SIZE) + z * CHUNK_SIZE + x) as usize
@@ -144,32 +113,59 @@ impl Chunk {
 
     #[inline]
     pub fn get_block(&self, x: u32, y: u32, z: u32) -> BlockID {
-        self.blocks[Chunk::coords_to_index(x, y, z)]
+        self.blocks.get(ChunkData::coords_to_index(x, y, z))
     }
 
     #[inline]
     pub fn set_block(&mut self, x: u32, y: u32, z: u32, block: BlockID) {
-        self.blocks[Chunk::coords_to_index(x, y, z)] = block;
-        self.dirty = true;
+        self.blocks.set(ChunkData::coords_to_index(x, y, z), block);
+        self.modified = true;
+        self.mark_dirty(x, y, z);
+    }
 
-        // block-face curling
-        if x == 0 {
-            self.dirty_neighbours.insert((-1, 0, 0));
-        } else if x == 15 {
-            self.dirty_neighbours.insert((1, 0, 0));
-        }
+    #[inline]
+    pub fn get_light(&self, kind: LightKind, x: u32, y: u32, z: u32) -> u8 {
+        self.light.get(kind, ChunkData::coords_to_index(x, y, z))
+    }
 
-        if y == 0 {
-            self.dirty_neighbours.insert((0, -1, 0));
-        } else if y == 15 {
-            self.dirty_neighbours.insert((0, 1, 0));
-        }
+    // Sky light << 4 | block light
+    #[inline]
+    pub fn get_packed_light(&self, x: u32, y: u32, z: u32) -> u8 {
+        self.light.packed(ChunkData::coords_to_index(x, y, z))
+    }
+
+    // Light isn't saved, so this doesn't mark the chunk as modified
+    #[inline]
+    pub fn set_light(&mut self, kind: LightKind, x: u32, y: u32, z: u32, value: u8) {
+        self.light.set(kind, ChunkData::coords_to_index(x, y, z), value);
+        self.mark_dirty(x, y, z);
+    }
 
-        if z == 0 {
-            self.dirty_neighbours.insert((0, 0, -1));
-        } else if z == 15 {
-            self.dirty_neighbours.insert((0, 0, 1));
+    fn mark_dirty(&mut self, x: u32, y: u32, z: u32) {
+        self.dirty = true;
+
+        // block-face curling and the ambient occlusion of the neighbours, diagonal ones included
+        let towards_edge = |v: u32| match v {
+            0 => -1,
+            15 => 1,
+            _ => 0,
+        };
+        let (ex, ey, ez) = (towards_edge(x), towards_edge(y), towards_edge(z));
+
+        for dx in [0, ex] {
+            for dy in [0, ey] {
+                for dz in [0, ez] {
+                    if (dx, dy, dz) != (0, 0, 0) {
+                        self.dirty_neighbours.insert((dx, dy, dz));
+                    }
+                }
+            }
         }
     }
 
+    // Bytes used by the block storage (the flat array used CHUNK_VOLUME * size_of::<BlockID>())
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.memory_usage()
+    }
+
 }
\ No newline at end of file
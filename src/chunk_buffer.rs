@@ -0,0 +1,259 @@
+use crate::chunk_manager::CHUNK_SIZE;
+use crate::chunk_mesh::{QuadIndexBuffer, INITIAL_QUADS};
+use crate::shapes::VERTEX_WORDS;
+use std::os::raw::c_void;
+
+// Vertices the shared buffer starts with (8 MiB), doubled whenever it runs out
+const INITIAL_CAPACITY: u32 = 1 << 20;
+
+const VERTEX_BYTES: usize = VERTEX_WORDS * std::mem::size_of::<u32>();
+
+// Range of vertices in the shared buffer
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Allocation {
+    pub offset: u32,
+    pub len: u32,
+}
+
+// First fit allocator over a range of vertices. Free ranges are kept sorted by offset and
+// merged with their neighbours when freed, so the buffer doesn't fragment into tiny holes.
+pub struct FreeList {
+    free: Vec<Allocation>,
+    capacity: u32,
+}
+
+impl FreeList {
+    pub fn new(capacity: u32) -> FreeList {
+        FreeList {
+            free: vec![Allocation { offset: 0, len: capacity }],
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn allocate(&mut self, len: u32) -> Option<Allocation> {
+        let i = self.free.iter().position(|range| range.len >= len)?;
+        let range = &mut self.free[i];
+        let allocation = Allocation { offset: range.offset, len };
+
+        range.offset += len;
+        range.len -= len;
+        if range.len == 0 {
+            self.free.remove(i);
+        }
+
+        Some(allocation)
+    }
+
+    pub fn free(&mut self, allocation: Allocation) {
+        if allocation.len == 0 {
+            return;
+        }
+
+        let i = self.free.partition_point(|range| range.offset < allocation.offset);
+        self.free.insert(i, allocation);
+
+        // Merge with the next range, then with the previous one
+        if i + 1 < self.free.len() && self.free[i].offset + self.free[i].len == self.free[i + 1].offset {
+            self.free[i].len += self.free[i + 1].len;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].offset + self.free[i - 1].len == self.free[i].offset {
+            self.free[i - 1].len += self.free[i].len;
+            self.free.remove(i);
+        }
+    }
+
+    // Adds the vertices between the old and the new capacity at the end
+    pub fn grow(&mut self, capacity: u32) {
+        let old_capacity = self.capacity;
+        self.capacity = capacity;
+        self.free(Allocation { offset: old_capacity, len: capacity - old_capacity });
+    }
+}
+
+// Read by MultiDrawElementsIndirect, laid out as OpenGL expects
+#[repr(C)]
+struct DrawElementsIndirectCommand {
+    count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    base_instance: u32,
+}
+
+fn create_vertex_buffer(capacity: u32) -> u32 {
+    let mut vbo = 0;
+    gl_call!(gl::CreateBuffers(1, &mut vbo));
+    gl_call!(gl::NamedBufferData(
+        vbo,
+        (capacity as usize * VERTEX_BYTES) as isize,
+        std::ptr::null(),
+        gl::DYNAMIC_DRAW
+    ));
+    vbo
+}
+
+// Vertices of every chunk mesh in one buffer, so all the visible chunks are drawn with a single
+// MultiDrawElementsIndirect call. Each draw reads its chunk's origin from the `ChunkOrigins`
+// storage buffer (binding 0) at gl_DrawID.
+pub struct ChunkBuffer {
+    vao: u32,
+    vbo: u32,
+    indirect_buffer: u32,
+    origin_buffer: u32,
+    quad_indices: QuadIndexBuffer,
+    allocator: FreeList,
+}
+
+impl ChunkBuffer {
+    pub fn new() -> ChunkBuffer {
+        let quad_indices = QuadIndexBuffer::new(INITIAL_QUADS);
+        let vbo = create_vertex_buffer(INITIAL_CAPACITY);
+
+        let mut vao = 0;
+        gl_call!(gl::CreateVertexArrays(1, &mut vao));
+
+        // Packed vertex, see shapes::VERTEX_WORDS
+        gl_call!(gl::EnableVertexArrayAttrib(vao, 0));
+        gl_call!(gl::VertexArrayAttribIFormat(vao, 0, VERTEX_WORDS as i32, gl::UNSIGNED_INT, 0));
+        gl_call!(gl::VertexArrayAttribBinding(vao, 0, 0));
+        gl_call!(gl::VertexArrayVertexBuffer(vao, 0, vbo, 0, VERTEX_BYTES as i32));
+        gl_call!(gl::VertexArrayElementBuffer(vao, quad_indices.ebo));
+
+        let mut buffers = [0; 2];
+        gl_call!(gl::CreateBuffers(2, buffers.as_mut_ptr()));
+
+        ChunkBuffer {
+            vao,
+            vbo,
+            indirect_buffer: buffers[0],
+            origin_buffer: buffers[1],
+            quad_indices,
+            allocator: FreeList::new(INITIAL_CAPACITY),
+        }
+    }
+
+    // Copies the packed vertices of a mesh into the buffer
+    pub fn upload(&mut self, vertices: &[u32]) -> Allocation {
+        let len = (vertices.len() / VERTEX_WORDS) as u32;
+
+        // Every quad of a draw needs its indices, however many quads a block model has
+        if len / 4 > self.quad_indices.quads {
+            self.quad_indices = QuadIndexBuffer::new((self.quad_indices.quads * 2).max(len / 4));
+            gl_call!(gl::VertexArrayElementBuffer(self.vao, self.quad_indices.ebo));
+        }
+
+        let allocation = match self.allocator.allocate(len) {
+            Some(allocation) => allocation,
+            None => {
+                self.grow((self.allocator.capacity() * 2).max(self.allocator.capacity() + len));
+                self.allocator.allocate(len).unwrap()
+            }
+        };
+
+        self.write(allocation, vertices);
+        allocation
+    }
+
+    // Overwrites the vertices of an allocation, e.g. sorted again
+    pub fn write(&self, allocation: Allocation, vertices: &[u32]) {
+        gl_call!(gl::NamedBufferSubData(
+            self.vbo,
+            (allocation.offset as usize * VERTEX_BYTES) as isize,
+            std::mem::size_of_val(vertices) as isize,
+            vertices.as_ptr() as *const c_void
+        ));
+    }
+
+    pub fn free(&mut self, allocation: Allocation) {
+        self.allocator.free(allocation);
+    }
+
+    // Moves the vertices to a bigger buffer, keeping their offsets
+    fn grow(&mut self, capacity: u32) {
+        let vbo = create_vertex_buffer(capacity);
+        gl_call!(gl::CopyNamedBufferSubData(
+            self.vbo,
+            vbo,
+            0,
+            0,
+            (self.allocator.capacity() as usize * VERTEX_BYTES) as isize
+        ));
+        gl_call!(gl::DeleteBuffers(1, &self.vbo));
+
+        self.vbo = vbo;
+        gl_call!(gl::VertexArrayVertexBuffer(self.vao, 0, vbo, 0, VERTEX_BYTES as i32));
+        self.allocator.grow(capacity);
+    }
+
+    // Draws the given meshes in order, one (chunk coords, allocation) per draw
+    pub fn draw(&self, meshes: &[((i32, i32, i32), Allocation)]) {
+        if meshes.is_empty() {
+            return;
+        }
+
+        let size = CHUNK_SIZE as i32;
+        let mut commands = Vec::with_capacity(meshes.len());
+        let mut origins = Vec::with_capacity(meshes.len() * 4);
+
+        for ((x, y, z), allocation) in meshes {
+            commands.push(DrawElementsIndirectCommand {
+                count: allocation.len / 4 * 6,
+                instance_count: 1,
+                first_index: 0,
+                base_vertex: allocation.offset as i32,
+                base_instance: 0,
+            });
+            // ivec4 for std430 alignment
+            origins.extend_from_slice(&[x * size, y * size, z * size, 0]);
+        }
+
+        // Orphaned every frame, the driver hands out fresh storage
+        gl_call!(gl::NamedBufferData(
+            self.indirect_buffer,
+            (commands.len() * std::mem::size_of::<DrawElementsIndirectCommand>()) as isize,
+            commands.as_ptr() as *const c_void,
+            gl::STREAM_DRAW
+        ));
+        gl_call!(gl::NamedBufferData(
+            self.origin_buffer,
+            (origins.len() * std::mem::size_of::<i32>()) as isize,
+            origins.as_ptr() as *const c_void,
+            gl::STREAM_DRAW
+        ));
+
+        gl_call!(gl::BindVertexArray(self.vao));
+        gl_call!(gl::BindBuffer(gl::DRAW_INDIRECT_BUFFER, self.indirect_buffer));
+        gl_call!(gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 0, self.origin_buffer));
+        gl_call!(gl::MultiDrawElementsIndirect(
+            gl::TRIANGLES,
+            gl::UNSIGNED_INT,
+            std::ptr::null(),
+            commands.len() as i32,
+            0
+        ));
+    }
+
+    // Bytes of the shared index buffer
+    pub fn index_bytes(&self) -> usize {
+        self.quad_indices.size_bytes()
+    }
+
+    // Bytes of the vertex buffer, used or not, and the shared index buffer
+    pub fn size_bytes(&self) -> usize {
+        self.allocator.capacity() as usize * VERTEX_BYTES + self.quad_indices.size_bytes()
+    }
+}
+
+impl Drop for ChunkBuffer {
+    fn drop(&mut self) {
+        gl_call!(gl::DeleteBuffers(1, &self.vbo));
+        gl_call!(gl::DeleteBuffers(1, &self.indirect_buffer));
+        gl_call!(gl::DeleteBuffers(1, &self.origin_buffer));
+        gl_call!(gl::DeleteVertexArrays(1, &self.vao));
+    }
+}
@@ -1,102 +1,658 @@
-use crate::shader::ShaderProgram;
-use crate::UVCoords;
+use crate::block_registry::BlockRegistry;
+use crate::aabb::AABB;
+use crate::chunk_buffer::ChunkBuffer;
+use crate::frustum::Frustum;
+use crate::heightmap::{ColumnHeightmap, HeightmapKind};
+use crate::jobs::{JobKind, JobOutput, JobResult, JobSystem};
+use crate::light::{remove_light, spread_light, LightKind, LightWorld, MAX_LIGHT};
+use crate::mesher::{sort_back_to_front, ChunkSnapshot, MeshData, MAX_LOD, NEIGHBOUR_OFFSETS};
+use crate::shapes::VERTEX_WORDS;
+use crate::structure::PendingBlocks;
+use crate::visibility::{opposite, VisibilityGraph};
+use crate::biome::Biome;
+use crate::world_gen::{GeneratedChunk, WorldGenerator};
+use crate::world_storage::WorldStorage;
 use crate::{
-    chunk::{BlockID, Chunk},
-    shapes::write_unit_cube_to_ptr,
+    chunk::{BlockID, ChunkData},
+    chunk_mesh::{ChunkMesh, TranslucentMesh},
 };
-use nalgebra::Matrix4;
-use nalgebra_glm::vec3;
-use noise::{NoiseFn, SuperSimplex};
+use nalgebra_glm::{vec3, Vec3};
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
-use crate::block_texture_sides::{BlockFaces, get_uv_every_side};
-use rand::random;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::Arc;
 
 pub const CHUNK_SIZE: u32 = 16;
 pub const CHUNK_VOLUME: u32 = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
 
 pub type Sides = [bool; 6];
 
+// Blocks the camera moves before the translucent faces of a chunk are sorted again
+const RESORT_DISTANCE: f32 = 1.0;
+
+pub struct StreamingConfig {
+    pub render_distance: i32, // in chunks, horizontally
+    pub min_chunk_y: i32,
+    pub max_chunk_y: i32,
+    // Per-frame budgets so streaming never stalls a frame. Chunks are requested and lit
+    // chunks_per_frame at a time.
+    pub chunks_per_frame: usize,
+    pub meshes_per_frame: usize,
+    // Horizontal distances in chunks from which meshes are built from cells of 2, 4 and 8 blocks.
+    // Ascending, and within the render distance to have any effect.
+    pub lod_distances: [i32; MAX_LOD as usize],
+}
+
+impl StreamingConfig {
+    // Level of detail of the chunk at `coords` seen from the chunk `center`, 0 is full detail
+    pub fn lod_at(&self, (x, _, z): (i32, i32, i32), center: (i32, i32, i32)) -> u32 {
+        let distance_squared = (x - center.0).pow(2) + (z - center.2).pow(2);
+        self.lod_distances.iter().filter(|&&d| distance_squared >= d * d).count() as u32
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.lod_distances.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(format!("lod_distances {:?} are not ascending", self.lod_distances));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            render_distance: 16,
+            min_chunk_y: 0,
+            max_chunk_y: 15,
+            chunks_per_frame: 32,
+            meshes_per_frame: 16,
+            // Full detail up to 8 chunks, then coarser out to the render distance
+            lod_distances: [8, 11, 14],
+        }
+    }
+}
+
+// Chunks handled by the last render_loaded_chunks
+#[derive(Copy, Clone, Default, Debug)]
+pub struct RenderStats {
+    pub drawn: u32,
+    // Outside the view frustum
+    pub culled: u32,
+    // In the frustum but hidden behind terrain
+    pub occluded: u32,
+}
+
 pub struct ChunkManager {
-    pub loaded_chunks: HashMap<(i32, i32, i32), Chunk>,
+    pub loaded_chunks: HashMap<(i32, i32, i32), ChunkData>,
+    // GPU buffers keyed by chunk coordinates. Filled by receive_jobs once a chunk is meshed,
+    // so world logic never touches OpenGL.
+    pub meshes: HashMap<(i32, i32, i32), ChunkMesh>,
+    // Holds the vertices of all the meshes, created with the first one
+    chunk_buffer: Option<ChunkBuffer>,
+    // Of the meshed chunks, for cave culling
+    visibility: HashMap<(i32, i32, i32), VisibilityGraph>,
+    // Level of detail each chunk was last sent to be meshed at
+    lods: HashMap<(i32, i32, i32), u32>,
+    // Structure blocks for chunks that aren't loaded, placed once they are
+    pub pending_blocks: PendingBlocks,
+    // Edited chunks unloaded while a worker saves them, with the batch they were sent in. Coming
+    // back in range, they are taken from here instead of the disk.
+    saving: HashMap<(i32, i32, i32), (u64, Arc<ChunkData>)>,
+    next_save_batch: u64,
+    // Chunks the workers finished that aren't inserted yet. Lighting them runs on this thread, so
+    // receive_jobs only inserts a few per frame.
+    arrived: HashMap<(i32, i32, i32), GeneratedChunk>,
+    // Keyed by (chunk x, chunk z)
+    heightmaps: HashMap<(i32, i32), ColumnHeightmap>,
+    registry: Arc<BlockRegistry>,
+    // Same one as the workers generate chunks with
+    generator: Arc<dyn WorldGenerator>,
 }
 
 impl ChunkManager {
-    pub fn new() -> ChunkManager {
+    pub fn new(registry: Arc<BlockRegistry>, generator: Arc<dyn WorldGenerator>) -> ChunkManager {
         ChunkManager {
             loaded_chunks: HashMap::new(),
+            meshes: HashMap::new(),
+            chunk_buffer: None,
+            visibility: HashMap::new(),
+            lods: HashMap::new(),
+            pending_blocks: PendingBlocks::new(),
+            saving: HashMap::new(),
+            next_save_batch: 0,
+            arrived: HashMap::new(),
+            heightmaps: HashMap::new(),
+            registry,
+            generator,
         }
     }
 
-    pub fn preload_some_chunks(&mut self) {
+    pub fn preload_some_chunks(&mut self, registry: &BlockRegistry) {
+        let choices: Vec<BlockID> = ["dirt", "cobblestone", "obsidian"]
+            .iter()
+            .filter_map(|&name| registry.by_name(name))
+            .collect();
+
         for y in 0..2 {
             for z in 0..2 {
                 for x in 0..2 {
-                    self.loaded_chunks.insert((x, y, z), Chunk::random());
+                    self.insert_chunk((x, y, z), ChunkData::random(&choices));
                 }
             }
         }
     }
 
-    pub fn simplex(&mut self) {
-        let ss = SuperSimplex::new(1296);
-        let n = 5;
+    pub fn chunk_coords_of(position: &Vec3) -> (i32, i32, i32) {
+        let (x, y, z) = (position.x.floor() as i32, position.y.floor() as i32, position.z.floor() as i32);
+        let (chunk_x, chunk_y, chunk_z, _, _, _) = ChunkManager::get_chunk_and_block_coords(x, y, z);
 
-        for y in 0..16 {
-            for z in -n..=n {
-                for x in -n..=n {
-                    self.loaded_chunks.insert((x, y, z), Chunk::empty());
-                }
+        (chunk_x, chunk_y, chunk_z)
+    }
+
+    fn distance_squared((x, y, z): (i32, i32, i32), (cx, cy, cz): (i32, i32, i32)) -> i32 {
+        (x - cx).pow(2) + (y - cy).pow(2) + (z - cz).pow(2)
+    }
+
+    // Loads the chunks within the render distance of `position`, nearest first, and unloads the
+    // ones that left it. `jobs` loads saved chunks and generates the others in the background,
+    // and saves the edited chunks that are unloaded.
+    pub fn update_streaming(&mut self, position: &Vec3, config: &StreamingConfig, jobs: &mut JobSystem) {
+        let center = ChunkManager::chunk_coords_of(position);
+        let r = config.render_distance;
+        let in_range = |(x, _, z): (i32, i32, i32)| (x - center.0).pow(2) + (z - center.2).pow(2) <= r * r;
+
+        let unloaded: Vec<(i32, i32, i32)> = self.loaded_chunks.keys().copied().filter(|&c| !in_range(c)).collect();
+
+        let mut modified = Vec::new();
+
+        for coords in unloaded {
+            if let Some(chunk) = self.remove_chunk(coords).filter(|chunk| chunk.modified) {
+                modified.push((coords, Arc::new(chunk)));
+            }
+            jobs.cancel(coords);
+        }
+
+        if !modified.is_empty() {
+            let batch = self.next_save_batch;
+            self.next_save_batch += 1;
+
+            for (coords, chunk) in &modified {
+                self.saving.insert(*coords, (batch, chunk.clone()));
             }
+            jobs.submit_save(batch, modified);
         }
 
-        for x in -16 * n..16 * n {
-            for z in -16 * n..16 * n {
-                let (xf, zf) = (x as f64 / 64.0, z as f64 / 64.0);
-                let y = ss.get([xf, zf]);
-                let y = (16.0 * (y + 1.0)) as i32;
+        // Chunks that left the render distance before they were generated or inserted
+        let abandoned: Vec<(i32, i32, i32)> = jobs.pending_chunks(JobKind::Generate).filter(|&c| !in_range(c)).collect();
 
-                self.set_block(x, y, z, BlockID::GrassBlock);
-                self.set_block(x, y - 1, z, BlockID::Dirt);
-                self.set_block(x, y - 2, z, BlockID::Dirt);
-                self.set_block(x, y - 3, z, BlockID::Cobblestone);
+        for coords in abandoned {
+            jobs.cancel(coords);
+        }
+        self.arrived.retain(|&coords, _| in_range(coords));
+
+        let mut missing = Vec::new();
 
-                if random::<u32>() % 100 == 0 {
-                    let h = 5;
+        for y in config.min_chunk_y..=config.max_chunk_y {
+            for z in center.2 - r..=center.2 + r {
+                for x in center.0 - r..=center.0 + r {
+                    let coords = (x, y, z);
 
-                    for i in y+1..y+1+h {
-                        self.set_block(x, i, z, BlockID::OakLog);
+                    if in_range(coords)
+                        && !self.loaded_chunks.contains_key(&coords)
+                        && !self.arrived.contains_key(&coords)
+                        && !jobs.is_pending(JobKind::Generate, coords)
+                    {
+                        missing.push(coords);
                     }
+                }
+            }
+        }
+
+        missing.sort_by_key(|&coords| ChunkManager::distance_squared(coords, center));
+
+        for coords in missing.into_iter().take(config.chunks_per_frame) {
+            // Still being saved, the disk may not have it yet. It stays modified, so it is saved again.
+            match self.saving.remove(&coords) {
+                Some((_, chunk)) => {
+                    let mut chunk = Arc::try_unwrap(chunk).unwrap_or_else(|chunk| (*chunk).clone());
+                    chunk.mark_all_dirty();
+                    self.insert_chunk(coords, chunk);
+                }
+                None => jobs.submit_generate(coords),
+            }
+        }
+    }
 
-                    for yy in y + h - 2 ..= y + h - 1 {
-                        for xx in x - 2..=x+2 {
-                            for zz in z-2 ..= z+2 {
-                                if xx != x || zz != z {
-                                    self.set_block(xx, yy, zz, BlockID::OakLeaves);
-                                }
-                            }
+    // Takes in the chunks and meshes finished by the workers. Meshes are uploaded here because
+    // only the main thread owns the OpenGL context. Of the finished chunks, at most
+    // `config.chunks_per_frame` are inserted per call, nearest to `center` first.
+    pub fn receive_jobs(&mut self, jobs: &mut JobSystem, center: (i32, i32, i32), config: &StreamingConfig) {
+        for JobResult { coords, output } in jobs.finished() {
+            match output {
+                JobOutput::Generated(generated) => {
+                    self.arrived.insert(coords, generated);
+                }
+                JobOutput::Loaded(chunk) => {
+                    self.arrived.insert(coords, GeneratedChunk { chunk, outside_blocks: Vec::new() });
+                }
+                JobOutput::Saved(batch, saved) => {
+                    // Chunks loaded again or unloaded into a newer batch since are not this save's
+                    for coords in saved {
+                        if self.saving.get(&coords).is_some_and(|&(b, _)| b == batch) {
+                            self.saving.remove(&coords);
                         }
                     }
+                }
+                JobOutput::Meshed(MeshData { vertices, translucent_vertices, unmerged_vertices, visibility, .. }) => {
+                    if self.loaded_chunks.contains_key(&coords) {
+                        self.visibility.insert(coords, visibility);
+                    }
+
+                    // The old vertices are freed first, so the new ones can take their place
+                    self.remove_mesh(coords);
+
+                    // Unloaded since, or nothing to draw
+                    if !self.loaded_chunks.contains_key(&coords) || (vertices.is_empty() && translucent_vertices.is_empty()) {
+                        continue;
+                    }
+
+                    let chunk_buffer = self.chunk_buffer.get_or_insert_with(ChunkBuffer::new);
+                    let translucent = (!translucent_vertices.is_empty()).then(|| TranslucentMesh {
+                        allocation: chunk_buffer.upload(&translucent_vertices),
+                        vertices: translucent_vertices,
+                        sorted_from: None,
+                    });
+
+                    self.meshes.insert(coords, ChunkMesh {
+                        allocation: chunk_buffer.upload(&vertices),
+                        vertices_drawn: (vertices.len() / VERTEX_WORDS) as u32,
+                        unmerged_vertices,
+                        translucent,
+                    });
+                }
+            }
+        }
+
+        let mut arrived: Vec<(i32, i32, i32)> = self.arrived.keys().copied().collect();
+        arrived.sort_by_key(|&coords| ChunkManager::distance_squared(coords, center));
+        arrived.truncate(config.chunks_per_frame);
+
+        for coords in arrived {
+            let GeneratedChunk { mut chunk, outside_blocks } = self.arrived.remove(&coords).unwrap();
+
+            // Generating it again would place these structures a second time
+            if !outside_blocks.is_empty() {
+                chunk.modified = true;
+            }
+
+            self.insert_chunk(coords, chunk);
+            self.place_structure_blocks(outside_blocks);
+        }
+    }
+
+    // Adds a loaded or generated chunk, with the structure blocks that were waiting for it
+    pub fn insert_chunk(&mut self, coords: (i32, i32, i32), mut chunk: ChunkData) {
+        if self.loaded_chunks.contains_key(&coords) {
+            self.remove_chunk(coords);
+        }
+
+        for ((x, y, z), block) in self.pending_blocks.remove(&coords).unwrap_or_default() {
+            if chunk.get_block(x, y, z).is_air() {
+                chunk.set_block(x, y, z, block);
+            }
+        }
+
+        let (cx, cy, cz) = coords;
+        let heightmap = self.heightmaps.entry((cx, cz)).or_insert_with(ColumnHeightmap::new);
+        heightmap.layers.insert(cy);
 
-                    for xx in x - 1 ..= x + 1 {
-                        for zz in z - 1 ..= z + 1 {
-                            if xx != x || zz != z {
-                                self.set_block(xx, y+h, zz, BlockID::OakLeaves);
-                            }
+        // Raise the columns whose highest block is now in this chunk
+        for z in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                for kind in HeightmapKind::ALL {
+                    let top = (0..CHUNK_SIZE)
+                        .rev()
+                        .find(|&y| ChunkManager::counts_for(&self.registry, kind, chunk.get_block(x, y, z)));
+
+                    if let Some(y) = top {
+                        let y = cy * CHUNK_SIZE as i32 + y as i32;
+
+                        if heightmap.get(kind, x, z).map_or(true, |height| y > height) {
+                            heightmap.set(kind, x, z, Some(y));
                         }
                     }
+                }
+            }
+        }
+
+        self.loaded_chunks.insert(coords, chunk);
+        self.light_chunk(coords);
+    }
+
+    // Lights a chunk that was just inserted: sky light above the columns, its own emitting blocks
+    // and the light coming in from the loaded neighbours. Chunks below lose the sky light this one
+    // now blocks.
+    fn light_chunk(&mut self, coords: (i32, i32, i32)) {
+        let registry = self.registry.clone();
+        let (cx, cy, cz) = coords;
+        let origin = (cx * CHUNK_SIZE as i32, cy * CHUNK_SIZE as i32, cz * CHUNK_SIZE as i32);
+        let to_world = |(x, y, z): (u32, u32, u32)| (origin.0 + x as i32, origin.1 + y as i32, origin.2 + z as i32);
+        let last = CHUNK_SIZE - 1;
+
+        let mut sky = VecDeque::new();
+        let mut block = VecDeque::new();
+        let mut shadowed = VecDeque::new();
+
+        let heightmap = &self.heightmaps[&(cx, cz)];
+        let chunk = self.loaded_chunks.get_mut(&coords).unwrap();
+
+        // Open sky down to the highest block it can't pass through
+        for z in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                let top = heightmap.get(HeightmapKind::LightBlocking, x, z);
+
+                for y in (0..CHUNK_SIZE).rev() {
+                    if top.map_or(false, |top| origin.1 + y as i32 <= top) {
+                        break;
+                    }
+
+                    chunk.set_light(LightKind::Sky, x, y, z, MAX_LIGHT);
+                }
+            }
+        }
+
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    let emission = registry.get(chunk.get_block(x, y, z)).light_emission;
+
+                    if emission > 0 {
+                        chunk.set_light(LightKind::Block, x, y, z, emission);
+                        block.push_back(to_world((x, y, z)));
+                    }
+
+                    if chunk.get_light(LightKind::Sky, x, y, z) != MAX_LIGHT {
+                        continue;
+                    }
+
+                    // Only the edges of the sky lit area can light anything new
+                    let on_border = [x, y, z].iter().any(|&v| v == 0 || v == last);
+                    let next_to_shade = || {
+                        NEIGHBOUR_OFFSETS.iter().any(|&(dx, dy, dz)| {
+                            let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+                            chunk.get_light(LightKind::Sky, nx as u32, ny as u32, nz as u32) != MAX_LIGHT
+                        })
+                    };
+
+                    if on_border || next_to_shade() {
+                        sky.push_back(to_world((x, y, z)));
+                    }
+                }
+            }
+        }
+
+        // The sky light of the chunk below went through where this chunk now has blocks
+        if let Some(below) = self.loaded_chunks.get(&(cx, cy - 1, cz)) {
+            let chunk = &self.loaded_chunks[&coords];
+
+            for z in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    if below.get_light(LightKind::Sky, x, last, z) == MAX_LIGHT
+                        && chunk.get_light(LightKind::Sky, x, 0, z) != MAX_LIGHT
+                    {
+                        shadowed.push_back(((origin.0 + x as i32, origin.1 - 1, origin.2 + z as i32), MAX_LIGHT));
+                    }
+                }
+            }
+        }
+
+        // Light coming in through the faces of the chunk
+        for (dx, dy, dz) in NEIGHBOUR_OFFSETS {
+            let neighbour = match self.loaded_chunks.get(&(cx + dx, cy + dy, cz + dz)) {
+                Some(neighbour) => neighbour,
+                None => continue,
+            };
+
+            // Layer of the neighbour touching this chunk
+            let touching = |d: i32| if d > 0 { 0 } else { last };
+
+            for a in 0..CHUNK_SIZE {
+                for b in 0..CHUNK_SIZE {
+                    let (x, y, z) = if dx != 0 {
+                        (touching(dx), a, b)
+                    } else if dy != 0 {
+                        (a, touching(dy), b)
+                    } else {
+                        (a, b, touching(dz))
+                    };
+                    let position = (
+                        origin.0 + dx * CHUNK_SIZE as i32 + x as i32,
+                        origin.1 + dy * CHUNK_SIZE as i32 + y as i32,
+                        origin.2 + dz * CHUNK_SIZE as i32 + z as i32,
+                    );
+
+                    if neighbour.get_light(LightKind::Sky, x, y, z) > 0 {
+                        sky.push_back(position);
+                    }
+                    if neighbour.get_light(LightKind::Block, x, y, z) > 0 {
+                        block.push_back(position);
+                    }
+                }
+            }
+        }
+
+        for &(position, _) in shadowed.iter() {
+            self.set_light_at(LightKind::Sky, position, 0);
+        }
+
+        remove_light(self, &registry, LightKind::Sky, shadowed);
+        spread_light(self, &registry, LightKind::Sky, sky);
+        spread_light(self, &registry, LightKind::Block, block);
+    }
+
+    // Relights around a block that changed from `old` to `new`
+    fn update_light(&mut self, position: (i32, i32, i32), old: BlockID, new: BlockID) {
+        let registry = self.registry.clone();
+        let (old, new) = (registry.get(old), registry.get(new));
+
+        if old.transparent == new.transparent && old.light_emission == new.light_emission {
+            return;
+        }
+
+        for kind in LightKind::ALL {
+            let level = self.light_at(kind, position);
+            self.set_light_at(kind, position, 0);
+            remove_light(self, &registry, kind, VecDeque::from([(position, level)]));
+        }
+
+        if new.light_emission > 0 {
+            self.set_light_at(LightKind::Block, position, new.light_emission);
+            spread_light(self, &registry, LightKind::Block, VecDeque::from([position]));
+        }
+
+        // Nothing above to pass the sky light down, e.g. at the top of the loaded chunks
+        let (x, y, z) = position;
+        let open_sky = self.height_at(x, z, HeightmapKind::LightBlocking).map_or(true, |top| y > top);
+
+        if new.transparent && open_sky && self.light_at(LightKind::Sky, position) != MAX_LIGHT {
+            self.set_light_at(LightKind::Sky, position, MAX_LIGHT);
+            spread_light(self, &registry, LightKind::Sky, VecDeque::from([position]));
+        }
+    }
+
+    // Gives the vertices of a chunk's meshes back to the shared buffer
+    fn remove_mesh(&mut self, coords: (i32, i32, i32)) {
+        if let (Some(mesh), Some(chunk_buffer)) = (self.meshes.remove(&coords), self.chunk_buffer.as_mut()) {
+            chunk_buffer.free(mesh.allocation);
+
+            if let Some(translucent) = mesh.translucent {
+                chunk_buffer.free(translucent.allocation);
+            }
+        }
+    }
+
+    // Unloads a chunk without saving it, and hands it back
+    pub fn remove_chunk(&mut self, coords: (i32, i32, i32)) -> Option<ChunkData> {
+        let chunk = self.loaded_chunks.remove(&coords)?;
+
+        self.remove_mesh(coords);
+        self.visibility.remove(&coords);
+        self.lods.remove(&coords);
+
+        let (cx, cy, cz) = coords;
+        let (min_y, max_y) = (cy * CHUNK_SIZE as i32, (cy + 1) * CHUNK_SIZE as i32 - 1);
+
+        let heightmap = match self.heightmaps.get_mut(&(cx, cz)) {
+            Some(heightmap) => heightmap,
+            None => return Some(chunk),
+        };
+        heightmap.layers.remove(&cy);
+
+        if heightmap.layers.is_empty() {
+            self.heightmaps.remove(&(cx, cz));
+            return Some(chunk);
+        }
+
+        // Columns whose highest block was in the chunk fall back to the chunks below
+        for z in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                for kind in HeightmapKind::ALL {
+                    let height = self.heightmaps[&(cx, cz)].get(kind, x, z);
+
+                    if height.map_or(false, |y| (min_y..=max_y).contains(&y)) {
+                        let (wx, wz) = (cx * CHUNK_SIZE as i32 + x as i32, cz * CHUNK_SIZE as i32 + z as i32);
+                        let height = self.find_top(kind, wx, wz, min_y - 1);
+                        self.heightmaps.get_mut(&(cx, cz)).unwrap().set(kind, x, z, height);
+                    }
+                }
+            }
+        }
+
+        Some(chunk)
+    }
+
+    fn counts_for(registry: &BlockRegistry, kind: HeightmapKind, block: BlockID) -> bool {
+        match kind {
+            HeightmapKind::MotionBlocking => registry.is_solid(block),
+            HeightmapKind::Surface => !block.is_air(),
+            HeightmapKind::LightBlocking => !registry.is_transparent(block),
+        }
+    }
+
+    // Highest block of the loaded chunks at (x, z) that is at most `max_y` high
+    fn find_top(&self, kind: HeightmapKind, x: i32, z: i32, max_y: i32) -> Option<i32> {
+        let (cx, _, cz, bx, _, bz) = ChunkManager::get_chunk_and_block_coords(x, 0, z);
+        let heightmap = self.heightmaps.get(&(cx, cz))?;
+
+        for &cy in heightmap.layers.iter().rev() {
+            let chunk = &self.loaded_chunks[&(cx, cy, cz)];
+            let min_y = cy * CHUNK_SIZE as i32;
+
+            for y in (min_y..min_y + CHUNK_SIZE as i32).rev().filter(|&y| y <= max_y) {
+                if ChunkManager::counts_for(&self.registry, kind, chunk.get_block(bx, (y - min_y) as u32, bz)) {
+                    return Some(y);
+                }
+            }
+        }
+
+        None
+    }
+
+    // Highest block at (x, z) among the loaded chunks
+    pub fn height_at(&self, x: i32, z: i32, kind: HeightmapKind) -> Option<i32> {
+        let (cx, _, cz, bx, _, bz) = ChunkManager::get_chunk_and_block_coords(x, 0, z);
+
+        self.heightmaps.get(&(cx, cz)).and_then(|heightmap| heightmap.get(kind, bx, bz))
+    }
+
+    // Biome of the column at (x, z), loaded or not
+    pub fn biome_at(&self, x: i32, z: i32) -> Biome {
+        self.generator.biome_at(x, z)
+    }
 
-                    self.set_block(x, y+h+1, z, BlockID::OakLeaves);
-                    self.set_block(x+1, y+h+1, z, BlockID::OakLeaves);
-                    self.set_block(x-1, y+h+1, z, BlockID::OakLeaves);
-                    self.set_block(x, y+h+1, z+1, BlockID::OakLeaves);
-                    self.set_block(x, y+h+1, z-1, BlockID::OakLeaves);
+    // Every chunk of the column within the streamed height range is loaded
+    pub fn is_column_loaded(&self, chunk_x: i32, chunk_z: i32, config: &StreamingConfig) -> bool {
+        (config.min_chunk_y..=config.max_chunk_y).all(|cy| self.loaded_chunks.contains_key(&(chunk_x, cy, chunk_z)))
+    }
 
+    // Places structure blocks (world coordinates) into air, or queues them if their chunk isn't loaded
+    pub fn place_structure_blocks(&mut self, blocks: Vec<((i32, i32, i32), BlockID)>) {
+        for ((x, y, z), block) in blocks {
+            let (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z) = ChunkManager::get_chunk_and_block_coords(x, y, z);
+            let coords = (chunk_x, chunk_y, chunk_z);
+
+            match self.get_block(x, y, z) {
+                Some(existing) => {
+                    if existing.is_air() {
+                        self.set_block(x, y, z, block);
+                    }
                 }
+                None => self.pending_blocks.entry(coords).or_default().push(((block_x, block_y, block_z), block)),
+            }
+        }
+    }
+
+    // Saves the chunks edited since they were generated or loaded, and the unloaded ones whose
+    // save hasn't finished. The job system must be stopped first, so no worker writes them too.
+    pub fn save_all(&mut self, storage: &WorldStorage) -> io::Result<()> {
+        storage.save_chunks(
+            self.loaded_chunks
+                .iter()
+                .filter(|(_, chunk)| chunk.modified)
+                .map(|(&coords, chunk)| (coords, chunk))
+                .chain(self.saving.iter().map(|(&coords, (_, chunk))| (coords, chunk.as_ref()))),
+        )?;
+
+        for chunk in self.loaded_chunks.values_mut() {
+            chunk.modified = false;
+        }
+        self.saving.clear();
+
+        Ok(())
+    }
+
+    // Replaces the chunk at `coords` with its saved version. Returns false if it was never saved.
+    pub fn load_chunk(&mut self, storage: &WorldStorage, coords: (i32, i32, i32)) -> io::Result<bool> {
+        match storage.load_chunk(coords)? {
+            Some(chunk) => {
+                self.insert_chunk(coords, chunk);
+                Ok(true)
             }
+            None => Ok(false),
         }
     }
 
+    // (drawn, without greedy meshing) vertices over all chunk meshes
+    pub fn mesh_vertex_counts(&self) -> (u64, u64) {
+        self.meshes.values().fold((0, 0), |(drawn, unmerged), mesh| {
+            (drawn + mesh.total_vertices() as u64, unmerged + mesh.unmerged_vertices as u64)
+        })
+    }
+
+    // (indexed, unindexed) bytes of the chunk meshes on the GPU: 4 vertices per quad and the shared
+    // index buffer, against the 6 vertices per quad drawn without indices
+    pub fn mesh_memory_usage(&self) -> (usize, usize) {
+        let vertex_bytes = VERTEX_WORDS * std::mem::size_of::<u32>();
+        let quads: usize = self.meshes.values().map(|mesh| mesh.total_vertices() as usize / 4).sum();
+        let indices = self.chunk_buffer.as_ref().map_or(0, ChunkBuffer::index_bytes);
+
+        (quads * 4 * vertex_bytes + indices, quads * 6 * vertex_bytes)
+    }
+
+    // Bytes allocated for the shared chunk buffer, including the space not used by any mesh
+    pub fn chunk_buffer_size(&self) -> usize {
+        self.chunk_buffer.as_ref().map_or(0, |chunk_buffer| chunk_buffer.size_bytes())
+    }
+
+    // (palette storage, flat array) bytes over all loaded chunks
+    pub fn block_memory_usage(&self) -> (usize, usize) {
+        let palette = self.loaded_chunks.values().map(|chunk| chunk.memory_usage()).sum();
+        let flat = self.loaded_chunks.len() * CHUNK_VOLUME as usize * std::mem::size_of::<BlockID>();
+
+        (palette, flat)
+    }
+
     fn get_chunk_and_block_coords(x: i32, y: i32, z: i32) -> (i32, i32, i32, u32, u32, u32) {
         let chunk_x = if x < 0 { (x + 1) / 16 - 1 } else { x / 16 };
         let chunk_y = if y < 0 { (y + 1) / 16 - 1 } else { y / 16 };
@@ -109,16 +665,6 @@ impl ChunkManager {
         (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z)
     }
 
-    fn get_global_coords(
-        (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z): (i32, i32, i32, u32, u32, u32),
-    ) -> (i32, i32, i32) {
-        let x = 16 * chunk_x + block_x as i32;
-        let y = 16 * chunk_y + block_y as i32;
-        let z = 16 * chunk_z + block_z as i32;
-
-        (x, y, z)
-    }
-
     pub fn get_block(&self, x: i32, y: i32, z: i32) -> Option<BlockID> {
         let (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z) =
             ChunkManager::get_chunk_and_block_coords(x, y, z);
@@ -132,157 +678,208 @@ impl ChunkManager {
         let (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z) =
             ChunkManager::get_chunk_and_block_coords(x, y, z);
 
-        self.loaded_chunks
-            .get_mut((chunk_x, chunk_y, chunk_z).borrow())
-            .map(|chunk| chunk.set_block(block_x, block_y, block_z, block));
-    }
+        let chunk = match self.loaded_chunks.get_mut((chunk_x, chunk_y, chunk_z).borrow()) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        let old = chunk.get_block(block_x, block_y, block_z);
+        chunk.set_block(block_x, block_y, block_z, block);
+
+        for kind in HeightmapKind::ALL {
+            let height = self.height_at(x, z, kind);
+
+            let new_height = if ChunkManager::counts_for(&self.registry, kind, block) {
+                Some(height.map_or(y, |height| height.max(y)))
+            } else if height == Some(y) {
+                // The top block was removed: look further down
+                self.find_top(kind, x, z, y - 1)
+            } else {
+                height
+            };
 
-    pub fn rebuild_dirty_chunks(&mut self, uv_map: &HashMap<BlockID, BlockFaces<UVCoords>>) {
-        let mut dirty_chunks = HashSet::new();
+            if new_height != height {
+                if let Some(heightmap) = self.heightmaps.get_mut(&(chunk_x, chunk_z)) {
+                    heightmap.set(kind, block_x, block_z, new_height);
+                }
+            }
+        }
+
+        self.update_light((x, y, z), old, block);
+    }
 
+    // Sends at most `config.meshes_per_frame` dirty chunks per call to be remeshed, nearest to
+    // `center` first, at the level of detail of their distance to it
+    pub fn rebuild_dirty_chunks(&mut self, jobs: &mut JobSystem, center: (i32, i32, i32), config: &StreamingConfig) {
         // Nearby chunks can be also dirty if the change happens at the edge
-        for (&(x, y, z), chunk) in self.loaded_chunks.iter() {
-            if chunk.dirty {
-                dirty_chunks.insert((x, y, z));
+        let mut dirty_neighbours = Vec::new();
+
+        for (&(x, y, z), chunk) in self.loaded_chunks.iter_mut() {
+            for (dx, dy, dz) in chunk.dirty_neighbours.drain() {
+                dirty_neighbours.push((x + dx, y + dy, z + dz));
             }
+        }
 
-            for &(dx, dy, dz) in chunk.dirty_neighbours.iter() {
-                dirty_chunks.insert((x + dx, y + dy, z + dz));
+        for coords in dirty_neighbours {
+            if let Some(chunk) = self.loaded_chunks.get_mut(&coords) {
+                chunk.dirty = true;
             }
         }
 
-        let mut active_sides: HashMap<(i32, i32, i32), Vec<Sides>> = HashMap::new();
+        // Chunks that crossed a distance ring since they were meshed. Seams don't depend on the
+        // neighbours' levels, so only the chunk itself is rebuilt.
+        for (coords, &lod) in &self.lods {
+            if config.lod_at(*coords, center) != lod {
+                if let Some(chunk) = self.loaded_chunks.get_mut(coords) {
+                    chunk.dirty = true;
+                }
+            }
+        }
 
-        for &coords in dirty_chunks.iter() {
-            let (cx, cy, cz) = coords;
-            let chunk = self.loaded_chunks.get(&coords);
+        let mut dirty_chunks: Vec<(i32, i32, i32)> = self
+            .loaded_chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.dirty)
+            .map(|(&coords, _)| coords)
+            .collect();
 
-            if let Some(chunk) = chunk {
-                let sides_vec = active_sides.entry(coords).or_default();
+        dirty_chunks.sort_by_key(|&coords| ChunkManager::distance_squared(coords, center));
+        dirty_chunks.truncate(config.meshes_per_frame);
 
-                for by in 0..CHUNK_SIZE {
-                    for bz in 0..CHUNK_SIZE {
-                        for bx in 0..CHUNK_SIZE {
-                            if !chunk.get_block(bx, by, bz).is_air() {
-                                let (gx, gy, gz) =
-                                    ChunkManager::get_global_coords((cx, cy, cz, bx, by, bz));
-                                sides_vec.push(self.get_active_sides_of_block(gx, gy, gz));
-                            }
-                        }
-                    }
-                }
+        for coords in dirty_chunks {
+            let snapshot = ChunkSnapshot::capture(&self.loaded_chunks, coords);
+            let lod = config.lod_at(coords, center);
+
+            if let Some(chunk) = self.loaded_chunks.get_mut(&coords) {
+                chunk.dirty = false;
+                self.lods.insert(coords, lod);
+                jobs.submit_mesh(coords, snapshot, lod);
             }
         }
+    }
 
-        for coords in dirty_chunks.iter() {
-            let chunk = self.loaded_chunks.get_mut(coords);
+    fn chunk_aabb((x, y, z): (i32, i32, i32)) -> AABB {
+        let mins = vec3(x as f32, y as f32, z as f32).scale(CHUNK_SIZE as f32);
+        AABB::new(mins, mins.add_scalar(CHUNK_SIZE as f32))
+    }
 
-            if let Some(chunk) = chunk {
+    // Chunks that can be seen from the camera's chunk: a breadth first search that only enters a
+    // neighbour through faces the current chunk connects to the one it was entered from, never
+    // turns back towards the camera and stays in the frustum. None if the camera's chunk isn't
+    // loaded.
+    fn visible_chunks(&self, camera: (i32, i32, i32), frustum: &Frustum) -> Option<HashSet<(i32, i32, i32)>> {
+        if !self.loaded_chunks.contains_key(&camera) {
+            return None;
+        }
 
-                chunk.dirty = false;
-                chunk.dirty_neighbours.clear();
-                chunk.vertices_drawn = 0;
+        let mut visible = HashSet::from([camera]);
+        // (chunk, side it was entered through, bit set of the directions travelled)
+        let mut queue = VecDeque::from([(camera, None, 0u8)]);
 
-                let sides = active_sides.get(coords).unwrap();
-                let n_visible_faces = sides.iter().map(|faces| faces.iter().fold(0, |acc, &x| acc + x as u32)).fold(0, |acc, n| acc + n);
+        while let Some(((x, y, z), entered, travelled)) = queue.pop_front() {
+            let graph = self.visibility.get(&(x, y, z)).copied().unwrap_or(VisibilityGraph::OPEN);
 
-                if n_visible_faces == 0 {
+            for (side, (dx, dy, dz)) in NEIGHBOUR_OFFSETS.into_iter().enumerate() {
+                let next = (x + dx, y + dy, z + dz);
+
+                if travelled & (1 << opposite(side)) != 0
+                    || entered.map_or(false, |from| !graph.connects(from, side))
+                    || visible.contains(&next)
+                    || !self.loaded_chunks.contains_key(&next)
+                    || !frustum.intersects(&ChunkManager::chunk_aabb(next))
+                {
                     continue;
                 }
 
-                // Initialize the vao
+                visible.insert(next);
+                queue.push_back((next, Some(opposite(side)), travelled | 1 << side));
+            }
+        }
 
-                gl_call!(gl::NamedBufferData(
-                    chunk.vbo,
-                    (100 * std::mem::size_of::<f32>() * n_visible_faces as usize) as isize,
-                    std::ptr::null(), 
-                    gl::DYNAMIC_DRAW
-                ));
+        Some(visible)
+    }
 
-                let vbo_ptr = gl_call!(gl::MapNamedBuffer(chunk.vbo, gl::WRITE_ONLY)) as *mut f32;
-                let mut idx = 0;
+    // Draws the meshes of the chunks that intersect `frustum` and can be seen from `camera`'s chunk:
+    // one indirect draw call for the opaque faces, then one for the translucent ones
+    pub fn render_loaded_chunks(&mut self, frustum: &Frustum, camera: &Vec3) -> RenderStats {
+        let mut stats = RenderStats::default();
+        let visible = self.visible_chunks(ChunkManager::chunk_coords_of(camera), frustum);
+        let mut draws = Vec::new();
+        let mut translucent = Vec::new();
+
+        for ((x, y, z), mesh) in &self.meshes {
+            if !frustum.intersects(&ChunkManager::chunk_aabb((*x, *y, *z))) {
+                stats.culled += 1;
+                continue;
+            }
 
-                let sides_vec = active_sides.get(coords).unwrap();
-                let mut cnt = 0;
+            if visible.as_ref().map_or(false, |visible| !visible.contains(&(*x, *y, *z))) {
+                stats.occluded += 1;
+                continue;
+            }
+            stats.drawn += 1;
 
-                for y in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        for x in 0..CHUNK_SIZE {
-                            let block = chunk.get_block(x, y, z);
+            if mesh.vertices_drawn > 0 {
+                draws.push(((*x, *y, *z), mesh.allocation));
+            }
+            if mesh.translucent.is_some() {
+                translucent.push((*x, *y, *z));
+            }
+        }
 
-                            if block != BlockID::Air {
-                                let active_sides = sides_vec[cnt];
+        let chunk_buffer = match &self.chunk_buffer {
+            Some(chunk_buffer) => chunk_buffer,
+            None => return stats,
+        };
+        chunk_buffer.draw(&draws);
 
-                                let uvs = uv_map.get(&block).unwrap().clone();
-                                let uvs = get_uv_every_side(uvs);
+        // Translucent faces blend over everything opaque without hiding each other, from the
+        // farthest chunk to the nearest
+        let origin = |(x, y, z): (i32, i32, i32)| vec3(x as f32, y as f32, z as f32).scale(CHUNK_SIZE as f32);
+        let distance = |coords| (origin(coords).add_scalar(CHUNK_SIZE as f32 / 2.0) - camera).norm_squared();
+        translucent.sort_by(|a, b| distance(*b).partial_cmp(&distance(*a)).unwrap_or(std::cmp::Ordering::Equal));
 
-                                let copied_vertices = unsafe { write_unit_cube_to_ptr(
-                                    vbo_ptr.offset(idx), (x as f32, y as f32, z as f32), uvs, active_sides)};
+        let mut translucent_draws = Vec::with_capacity(translucent.len());
 
-                                chunk.vertices_drawn += copied_vertices;
-                                idx += copied_vertices as isize * 5;
-                                cnt += 1;
-                            }
-                        }
-                    }
-                }
-                gl_call!(gl::UnmapNamedBuffer(chunk.vbo));
+        for coords in translucent {
+            let mesh = match self.meshes.get_mut(&coords).and_then(|mesh| mesh.translucent.as_mut()) {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+
+            if mesh.sorted_from.map_or(true, |sorted_from| (sorted_from - camera).norm() > RESORT_DISTANCE) {
+                sort_back_to_front(&mut mesh.vertices, &(camera - origin(coords)));
+                chunk_buffer.write(mesh.allocation, &mesh.vertices);
+                mesh.sorted_from = Some(*camera);
             }
+            translucent_draws.push((coords, mesh.allocation));
         }
+
+        gl_call!(gl::DepthMask(gl::FALSE));
+        chunk_buffer.draw(&translucent_draws);
+        gl_call!(gl::DepthMask(gl::TRUE));
+
+        stats
     }
+}
 
-    pub fn get_active_sides_of_block(&self, x: i32, y: i32, z: i32) -> Sides {
-        let right = self
-            .get_block(x + 1, y, z)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-        let left = self
-            .get_block(x - 1, y, z)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-        let top = self
-            .get_block(x, y + 1, z)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-        let bottom = self
-            .get_block(x, y - 1, z)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-        let front = self
-            .get_block(x, y, z + 1)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-        let back = self
-            .get_block(x, y, z - 1)
-            .filter(|&b| !b.is_transparent())
-            .is_none();
-
-        [right, left, top, bottom, front, back]
-    }
-
-    pub fn render_loaded_chunks(&mut self, program: &mut ShaderProgram) {
-        for ((x, y, z), chunk) in &self.loaded_chunks {
-            // Skip rendering the chunk if there is nothing to draw
-            if chunk.vertices_drawn == 0 {
-                continue;
-            }
-            
-            let model_matrix = {
-                let translate_matrix =
-                    Matrix4::new_translation(&vec3(*x as f32, *y as f32, *z as f32).scale(16.0));
-                let rotate_matrix = Matrix4::from_euler_angles(0.0f32, 0.0, 0.0);
-                let scale_matrix = Matrix4::new_nonuniform_scaling(&vec3(1.0f32, 1.0f32, 1.0f32));
-
-                translate_matrix * rotate_matrix * scale_matrix
-            };
+impl LightWorld for ChunkManager {
+    fn block_at(&self, (x, y, z): (i32, i32, i32)) -> Option<BlockID> {
+        self.get_block(x, y, z)
+    }
+
+    fn light_at(&self, kind: LightKind, (x, y, z): (i32, i32, i32)) -> u8 {
+        let (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z) = ChunkManager::get_chunk_and_block_coords(x, y, z);
+
+        self.loaded_chunks
+            .get(&(chunk_x, chunk_y, chunk_z))
+            .map_or(0, |chunk| chunk.get_light(kind, block_x, block_y, block_z))
+    }
+
+    fn set_light_at(&mut self, kind: LightKind, (x, y, z): (i32, i32, i32), value: u8) {
+        let (chunk_x, chunk_y, chunk_z, block_x, block_y, block_z) = ChunkManager::get_chunk_and_block_coords(x, y, z);
 
-            gl_call!(gl::BindVertexArray(chunk.vao));
-            program.set_uniform_matrix4fv("model", model_matrix.as_ptr());
-            gl_call!(gl::DrawArrays(
-                gl::TRIANGLES,
-                0,
-                chunk.vertices_drawn as i32
-            ));
+        if let Some(chunk) = self.loaded_chunks.get_mut(&(chunk_x, chunk_y, chunk_z)) {
+            chunk.set_light(kind, block_x, block_y, block_z, value);
         }
     }
 }
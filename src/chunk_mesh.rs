@@ -0,0 +1,71 @@
+use crate::chunk_buffer::Allocation;
+use crate::chunk_manager::CHUNK_VOLUME;
+use crate::shapes::{QUAD_INDICES, VERTEX_WORDS};
+use nalgebra_glm::Vec3;
+use std::os::raw::c_void;
+
+// Render-side counterpart of a ChunkData: where its vertices live in the shared ChunkBuffer.
+// The ChunkBuffer owns the GPU memory, so the allocations have to be freed there.
+pub struct ChunkMesh {
+    pub allocation: Allocation,
+    pub vertices_drawn: u32, // 4 per quad
+    pub unmerged_vertices: u32, // without greedy meshing, for comparison
+    pub translucent: Option<TranslucentMesh>,
+}
+
+impl ChunkMesh {
+    // Opaque and translucent
+    pub fn total_vertices(&self) -> u32 {
+        self.vertices_drawn + self.translucent.as_ref().map_or(0, |mesh| (mesh.vertices.len() / VERTEX_WORDS) as u32)
+    }
+}
+
+// Blended faces of a chunk, drawn after every opaque one. They have to be drawn back to front,
+// so a copy stays here to be sorted again when the camera moves.
+pub struct TranslucentMesh {
+    pub allocation: Allocation,
+    pub vertices: Vec<u32>,
+    // Camera position the quads were last sorted for
+    pub sorted_from: Option<Vec3>,
+}
+
+// Quads the index buffer starts with: six faces per cube, e.g. a chunk full of leaves. Block
+// models can have more quads per block, so the buffer grows to the biggest mesh uploaded.
+pub const INITIAL_QUADS: u32 = CHUNK_VOLUME * 6;
+
+// Element buffer shared by every chunk mesh: quad i is drawn from the vertices 4i..4i+3 with
+// QUAD_INDICES, so meshes only store 4 vertices per quad instead of 6.
+pub struct QuadIndexBuffer {
+    pub ebo: u32,
+    // Quads a single mesh may have
+    pub quads: u32,
+}
+
+impl QuadIndexBuffer {
+    pub fn new(quads: u32) -> QuadIndexBuffer {
+        let indices: Vec<u32> = (0..quads)
+            .flat_map(|quad| QUAD_INDICES.map(|i| quad * 4 + i))
+            .collect();
+
+        let mut ebo = 0;
+        gl_call!(gl::CreateBuffers(1, &mut ebo));
+        gl_call!(gl::NamedBufferStorage(
+            ebo,
+            (indices.len() * std::mem::size_of::<u32>()) as isize,
+            indices.as_ptr() as *const c_void,
+            0
+        ));
+
+        QuadIndexBuffer { ebo, quads }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.quads as usize * QUAD_INDICES.len() * std::mem::size_of::<u32>()
+    }
+}
+
+impl Drop for QuadIndexBuffer {
+    fn drop(&mut self) {
+        gl_call!(gl::DeleteBuffers(1, &self.ebo));
+    }
+}
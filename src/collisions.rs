@@ -1,4 +1,5 @@
 use nalgebra_glm::{vec3, Vec3};
+use crate::block_registry::BlockRegistry;
 use crate::chunk_manager::ChunkManager;
 use crate::{Player, PLAYER_HALF_HEIGHT, PLAYER_HALF_WIDTH, PLAYER_HEIGHT, PLAYER_WIDTH};
 use crate::aabb::AABB;
@@ -11,7 +12,7 @@ pub fn get_block_aabb(mins: &Vec3) -> AABB {
     )
 }
 
-pub fn player_collision_detection(player: &mut Player, chunk_manager: &ChunkManager) {
+pub fn player_collision_detection(player: &mut Player, chunk_manager: &ChunkManager, registry: &BlockRegistry) {
     let mag = player.velocity.magnitude();
 
     if mag > 0.1 {
@@ -40,7 +41,7 @@ pub fn player_collision_detection(player: &mut Player, chunk_manager: &ChunkMana
             for z in block_min.z ..= block_max.z {
                 for x in block_min.x..=block_max.x {
                     if let Some(block) = chunk_manager.get_block(x, y, z) {
-                        if block.is_air() {
+                        if !registry.is_solid(block) {
                             continue;
                         }
 
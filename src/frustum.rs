@@ -0,0 +1,103 @@
+use crate::aabb::AABB;
+use nalgebra_glm::{Mat4, Vec4};
+
+// The six planes of the camera's view volume, pointing inwards. A plane (a, b, c, d) keeps the
+// points with a * x + b * y + c * z + d >= 0.
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    // From a `projection * view` matrix: each plane is the last row plus or minus one of the others
+    // (Gribb & Hartmann)
+    pub fn from_matrix(matrix: &Mat4) -> Frustum {
+        let row = |i: usize| -> Vec4 { matrix.row(i).transpose() };
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+
+        let planes = [w + x, w - x, w + y, w - y, w + z, w - z].map(|plane| plane / plane.xyz().norm());
+
+        Frustum { planes }
+    }
+
+    // False only if the box is completely behind one of the planes. Boxes near the corners of the
+    // frustum can pass while being outside, which is fine for culling.
+    pub fn intersects(&self, aabb: &AABB) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner of the box furthest along the plane normal
+            let x = if plane.x > 0.0 { aabb.maxs.x } else { aabb.mins.x };
+            let y = if plane.y > 0.0 { aabb.maxs.y } else { aabb.mins.y };
+            let z = if plane.z > 0.0 { aabb.maxs.z } else { aabb.mins.z };
+
+            plane.x * x + plane.y * y + plane.z * z + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra_glm::{look_at, perspective, vec3, vec4, Vec3};
+
+    // 90 degrees both ways, so the side planes are at 45 degrees
+    fn frustum(eye: Vec3, target: Vec3) -> Frustum {
+        let projection = perspective(1.0, std::f32::consts::FRAC_PI_2, 0.1, 100.0);
+        Frustum::from_matrix(&(projection * look_at(&eye, &target, &vec3(0.0, 1.0, 0.0))))
+    }
+
+    fn cube(center: Vec3, half: f32) -> AABB {
+        AABB::new(center - vec3(half, half, half), center + vec3(half, half, half))
+    }
+
+    #[test]
+    fn extracts_normalized_planes() {
+        let frustum = frustum(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0));
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+
+        // Left, right, bottom, top, near, far, for a camera looking down -z
+        let expected = [
+            vec4(s, 0.0, -s, 0.0),
+            vec4(-s, 0.0, -s, 0.0),
+            vec4(0.0, s, -s, 0.0),
+            vec4(0.0, -s, -s, 0.0),
+            vec4(0.0, 0.0, -1.0, -0.1),
+            vec4(0.0, 0.0, 1.0, 100.0),
+        ];
+
+        for (plane, expected) in frustum.planes.iter().zip(expected) {
+            assert!((plane - expected).norm() < 1e-3, "{plane:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn culls_boxes_outside() {
+        let frustum = frustum(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0));
+
+        assert!(frustum.intersects(&cube(vec3(0.0, 0.0, -10.0), 0.5)), "in front");
+        assert!(!frustum.intersects(&cube(vec3(0.0, 0.0, 10.0), 0.5)), "behind");
+        assert!(!frustum.intersects(&cube(vec3(0.0, 0.0, -150.0), 0.5)), "beyond far");
+        assert!(!frustum.intersects(&cube(vec3(-14.0, 0.0, -10.0), 1.0)), "left");
+        assert!(!frustum.intersects(&cube(vec3(0.0, 14.0, -10.0), 1.0)), "above");
+    }
+
+    #[test]
+    fn keeps_boxes_straddling_a_plane() {
+        let frustum = frustum(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0));
+
+        // Around the camera, through the near plane
+        assert!(frustum.intersects(&cube(vec3(0.0, 0.0, 0.0), 1.0)));
+        // Through the left plane (x = z), the far plane and the top plane
+        assert!(frustum.intersects(&cube(vec3(-10.0, 0.0, -10.0), 1.0)));
+        assert!(frustum.intersects(&cube(vec3(0.0, 0.0, -100.0), 1.0)));
+        assert!(frustum.intersects(&cube(vec3(0.0, 20.0, -20.0), 1.0)));
+    }
+
+    #[test]
+    fn follows_the_camera() {
+        // Looking down +z from elsewhere
+        let frustum = frustum(vec3(5.0, 10.0, 5.0), vec3(5.0, 10.0, 15.0));
+
+        assert!(frustum.intersects(&cube(vec3(5.0, 10.0, 20.0), 0.5)));
+        assert!(!frustum.intersects(&cube(vec3(5.0, 10.0, -5.0), 0.5)));
+        assert!(!frustum.intersects(&cube(vec3(0.0, 0.0, -10.0), 0.5)));
+    }
+}
@@ -0,0 +1,44 @@
+use crate::chunk_manager::CHUNK_SIZE;
+use std::collections::BTreeSet;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum HeightmapKind {
+    // Highest block the player collides with
+    MotionBlocking,
+    // Highest block that isn't air
+    Surface,
+    // Highest block sky light can't pass through
+    LightBlocking,
+}
+
+impl HeightmapKind {
+    pub const ALL: [HeightmapKind; 3] = [HeightmapKind::MotionBlocking, HeightmapKind::Surface, HeightmapKind::LightBlocking];
+}
+
+const COLUMNS: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// Highest block of each of the 16x16 columns of a chunk column, over the chunks that are loaded.
+// Kept up to date by the ChunkManager as chunks load, unload and change.
+pub struct ColumnHeightmap {
+    // Indexed by z * 16 + x
+    heights: [[Option<i32>; COLUMNS]; HeightmapKind::ALL.len()],
+    // Chunk y of the loaded chunks of this column
+    pub layers: BTreeSet<i32>,
+}
+
+impl ColumnHeightmap {
+    pub fn new() -> ColumnHeightmap {
+        ColumnHeightmap {
+            heights: [[None; COLUMNS]; HeightmapKind::ALL.len()],
+            layers: BTreeSet::new(),
+        }
+    }
+
+    pub fn get(&self, kind: HeightmapKind, x: u32, z: u32) -> Option<i32> {
+        self.heights[kind as usize][(z * CHUNK_SIZE + x) as usize]
+    }
+
+    pub fn set(&mut self, kind: HeightmapKind, x: u32, z: u32, height: Option<i32>) {
+        self.heights[kind as usize][(z * CHUNK_SIZE + x) as usize] = height;
+    }
+}
@@ -0,0 +1,322 @@
+use crate::block_registry::BlockRegistry;
+use crate::chunk::ChunkData;
+use crate::mesher::{build_mesh, ChunkSnapshot, MeshData, UVMap};
+use crate::world_gen::{GeneratedChunk, WorldGenerator};
+use crate::world_storage::WorldStorage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+// Chunk loading, generation, meshing and saving run on worker threads. The main thread only
+// submits jobs, uploads finished meshes and inserts finished chunks.
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum JobKind {
+    // Loads the chunk if it was saved, generates it otherwise
+    Generate,
+    Mesh,
+    Save,
+}
+
+enum Work {
+    Generate,
+    // Snapshot and level of detail
+    Mesh(Box<ChunkSnapshot>, u32),
+    // Batch number and the unloaded chunks to write
+    Save(u64, Vec<((i32, i32, i32), Arc<ChunkData>)>),
+}
+
+struct Job {
+    coords: (i32, i32, i32),
+    sequence: u64,
+    cancelled: Arc<AtomicBool>,
+    work: Work,
+}
+
+pub enum JobOutput {
+    Generated(GeneratedChunk),
+    // Read back from the world storage
+    Loaded(ChunkData),
+    // Chunk-local vertices, see mesher::build_mesh
+    Meshed(MeshData),
+    // Batch number and the chunks now on disk
+    Saved(u64, Vec<(i32, i32, i32)>),
+}
+
+pub struct JobResult {
+    pub coords: (i32, i32, i32),
+    pub output: JobOutput,
+}
+
+struct Queue {
+    jobs: Mutex<Vec<Job>>,
+    available: Condvar,
+    shutdown: AtomicBool,
+    // Chunk the camera is in. Workers always take the job nearest to it.
+    camera: [AtomicI32; 3],
+}
+
+impl Queue {
+    fn camera(&self) -> (i32, i32, i32) {
+        (
+            self.camera[0].load(Ordering::Relaxed),
+            self.camera[1].load(Ordering::Relaxed),
+            self.camera[2].load(Ordering::Relaxed),
+        )
+    }
+
+    // Blocks until there is a job, None on shutdown. Saves go first, they hold unloaded chunks in memory.
+    fn pop_nearest(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        loop {
+            if self.shutdown.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            jobs.retain(|job| !job.cancelled.load(Ordering::Relaxed));
+
+            let (cx, cy, cz) = self.camera();
+            let nearest = jobs
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, job)| {
+                    let (x, y, z) = job.coords;
+                    (!matches!(job.work, Work::Save(..)), (x - cx).pow(2) + (y - cy).pow(2) + (z - cz).pow(2))
+                })
+                .map(|(i, _)| i);
+
+            match nearest {
+                Some(i) => return Some(jobs.swap_remove(i)),
+                None => jobs = self.available.wait(jobs).unwrap(),
+            }
+        }
+    }
+}
+
+// (sequence number, cancellation flag) of a submitted job
+type Ticket = (u64, Arc<AtomicBool>);
+
+pub struct JobSystem {
+    queue: Arc<Queue>,
+    workers: Vec<JoinHandle<()>>,
+    results: Receiver<(u64, JobKind, JobResult)>,
+    next_sequence: u64,
+    // Latest job per chunk and kind. Results of older or cancelled jobs are dropped.
+    pending: HashMap<(JobKind, (i32, i32, i32)), Ticket>,
+}
+
+impl JobSystem {
+    pub fn new(
+        threads: usize,
+        generator: Arc<dyn WorldGenerator>,
+        registry: Arc<BlockRegistry>,
+        uv_map: Arc<UVMap>,
+        storage: Option<Arc<WorldStorage>>,
+    ) -> JobSystem {
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(Vec::new()),
+            available: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            camera: [AtomicI32::new(0), AtomicI32::new(0), AtomicI32::new(0)],
+        });
+        let (sender, results) = channel();
+
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let queue = queue.clone();
+                let sender = sender.clone();
+                let generator = generator.clone();
+                let registry = registry.clone();
+                let uv_map = uv_map.clone();
+                let storage = storage.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("chunk-worker-{i}"))
+                    .spawn(move || worker(queue, sender, generator, registry, uv_map, storage))
+                    .expect("Failed to spawn a chunk worker")
+            })
+            .collect();
+
+        JobSystem {
+            queue,
+            workers,
+            results,
+            next_sequence: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn set_camera(&self, (x, y, z): (i32, i32, i32)) {
+        self.queue.camera[0].store(x, Ordering::Relaxed);
+        self.queue.camera[1].store(y, Ordering::Relaxed);
+        self.queue.camera[2].store(z, Ordering::Relaxed);
+    }
+
+    fn submit(&mut self, coords: (i32, i32, i32), kind: JobKind, work: Work) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        // A newer job supersedes the one still running for the same chunk
+        if let Some((_, old)) = self.pending.insert((kind, coords), (sequence, cancelled.clone())) {
+            old.store(true, Ordering::Relaxed);
+        }
+
+        self.queue.jobs.lock().unwrap().push(Job {
+            coords,
+            sequence,
+            cancelled,
+            work,
+        });
+        self.queue.available.notify_one();
+    }
+
+    pub fn submit_generate(&mut self, coords: (i32, i32, i32)) {
+        self.submit(coords, JobKind::Generate, Work::Generate);
+    }
+
+    pub fn submit_mesh(&mut self, coords: (i32, i32, i32), snapshot: ChunkSnapshot, lod: u32) {
+        self.submit(coords, JobKind::Mesh, Work::Mesh(Box::new(snapshot), lod));
+    }
+
+    // Saves are never cancelled or superseded, every batch reports back once written
+    pub fn submit_save(&mut self, batch: u64, chunks: Vec<((i32, i32, i32), Arc<ChunkData>)>) {
+        let coords = match chunks.first() {
+            Some(&(coords, _)) => coords,
+            None => return,
+        };
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.queue.jobs.lock().unwrap().push(Job {
+            coords,
+            sequence,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            work: Work::Save(batch, chunks),
+        });
+        self.queue.available.notify_one();
+    }
+
+    pub fn is_pending(&self, kind: JobKind, coords: (i32, i32, i32)) -> bool {
+        self.pending.contains_key(&(kind, coords))
+    }
+
+    pub fn pending_chunks(&self, kind: JobKind) -> impl Iterator<Item = (i32, i32, i32)> + '_ {
+        self.pending.keys().filter(move |(k, _)| *k == kind).map(|&(_, coords)| coords)
+    }
+
+    // Drops the jobs of a chunk, whether queued or running
+    pub fn cancel(&mut self, coords: (i32, i32, i32)) {
+        for kind in [JobKind::Generate, JobKind::Mesh] {
+            if let Some((_, cancelled)) = self.pending.remove(&(kind, coords)) {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Results finished since the last call, without the stale ones
+    pub fn finished(&mut self) -> Vec<JobResult> {
+        let mut finished = Vec::new();
+
+        while let Ok((sequence, kind, result)) = self.results.try_recv() {
+            let key = (kind, result.coords);
+
+            if kind == JobKind::Save {
+                finished.push(result);
+            } else if self.pending.get(&key).map(|&(latest, _)| latest) == Some(sequence) {
+                self.pending.remove(&key);
+                finished.push(result);
+            }
+        }
+
+        finished
+    }
+}
+
+impl Drop for JobSystem {
+    fn drop(&mut self) {
+        // Under the lock, so a worker can't miss the wakeup between checking the flag and waiting
+        {
+            let _jobs = self.queue.jobs.lock().unwrap();
+            self.queue.shutdown.store(true, Ordering::Relaxed);
+            self.queue.available.notify_all();
+        }
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker(
+    queue: Arc<Queue>,
+    sender: Sender<(u64, JobKind, JobResult)>,
+    generator: Arc<dyn WorldGenerator>,
+    registry: Arc<BlockRegistry>,
+    uv_map: Arc<UVMap>,
+    storage: Option<Arc<WorldStorage>>,
+) {
+    while let Some(job) = queue.pop_nearest() {
+        let (kind, output) = match job.work {
+            Work::Generate => {
+                let saved = match storage.as_ref().map(|storage| storage.load_chunk(job.coords)) {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(err)) => {
+                        println!("Failed to load chunk {:?}: {err}", job.coords);
+                        None
+                    }
+                    None => None,
+                };
+
+                match saved {
+                    Some(chunk) => (JobKind::Generate, JobOutput::Loaded(chunk)),
+                    None => {
+                        let mut generated = generator.generate_chunk(job.coords);
+                        // Generation is deterministic, nothing to save until the chunk is edited
+                        generated.chunk.modified = false;
+                        (JobKind::Generate, JobOutput::Generated(generated))
+                    }
+                }
+            }
+            Work::Save(batch, chunks) => {
+                let saved = match &storage {
+                    Some(storage) => storage.save_chunks(chunks.iter().map(|(coords, chunk)| (*coords, chunk.as_ref()))),
+                    None => Ok(()),
+                };
+
+                // Failed chunks stay with the chunk manager, to be saved again on exit
+                let saved = match saved {
+                    Ok(()) => chunks.into_iter().map(|(coords, _)| coords).collect(),
+                    Err(err) => {
+                        println!("Failed to save unloaded chunks: {err}");
+                        Vec::new()
+                    }
+                };
+
+                (JobKind::Save, JobOutput::Saved(batch, saved))
+            }
+            Work::Mesh(snapshot, lod) => {
+                (JobKind::Mesh, JobOutput::Meshed(build_mesh(&snapshot, &registry, &uv_map, lod)))
+            }
+        };
+
+        // Unloaded while we were working on it
+        if job.cancelled.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let result = JobResult {
+            coords: job.coords,
+            output,
+        };
+
+        if sender.send((job.sequence, kind, result)).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::block_registry::BlockRegistry;
+use crate::chunk::BlockID;
+use crate::chunk_manager::CHUNK_VOLUME;
+use crate::mesher::NEIGHBOUR_OFFSETS;
+use std::collections::VecDeque;
+
+pub const MAX_LIGHT: u8 = 15;
+
+// Index of the downward offset in NEIGHBOUR_OFFSETS
+const DOWN: usize = 3;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum LightKind {
+    // From the open sky, goes straight down without getting dimmer
+    Sky,
+    // From blocks with a light_emission
+    Block,
+}
+
+impl LightKind {
+    pub const ALL: [LightKind; 2] = [LightKind::Sky, LightKind::Block];
+}
+
+// Light level of every block of a chunk: sky light in the high 4 bits, block light in the low 4 bits.
+// Not saved: it is computed again when the chunk is loaded.
+#[derive(Clone)]
+pub struct LightMap {
+    data: Vec<u8>,
+}
+
+impl LightMap {
+    pub fn new() -> LightMap {
+        LightMap {
+            data: vec![0; CHUNK_VOLUME as usize],
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, kind: LightKind, index: usize) -> u8 {
+        match kind {
+            LightKind::Sky => self.data[index] >> 4,
+            LightKind::Block => self.data[index] & 0x0F,
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, kind: LightKind, index: usize, value: u8) {
+        let value = value.min(MAX_LIGHT);
+
+        self.data[index] = match kind {
+            LightKind::Sky => (self.data[index] & 0x0F) | (value << 4),
+            LightKind::Block => (self.data[index] & 0xF0) | value,
+        };
+    }
+
+    // Both levels as stored, sky << 4 | block
+    #[inline]
+    pub fn packed(&self, index: usize) -> u8 {
+        self.data[index]
+    }
+}
+
+// Blocks and light in world coordinates. Positions that aren't loaded have no block and stop the light.
+pub trait LightWorld {
+    fn block_at(&self, position: (i32, i32, i32)) -> Option<BlockID>;
+    fn light_at(&self, kind: LightKind, position: (i32, i32, i32)) -> u8;
+    fn set_light_at(&mut self, kind: LightKind, position: (i32, i32, i32), value: u8);
+}
+
+fn neighbours((x, y, z): (i32, i32, i32)) -> impl Iterator<Item = (usize, (i32, i32, i32))> {
+    NEIGHBOUR_OFFSETS
+        .into_iter()
+        .enumerate()
+        .map(move |(side, (dx, dy, dz))| (side, (x + dx, y + dy, z + dz)))
+}
+
+// Light a neighbour gets from a block with `level`
+fn passed_on(kind: LightKind, side: usize, level: u8) -> u8 {
+    if kind == LightKind::Sky && side == DOWN && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+// Breadth first flood fill from the queued positions, which already hold their light.
+// Light only enters transparent blocks.
+pub fn spread_light(
+    world: &mut impl LightWorld,
+    registry: &BlockRegistry,
+    kind: LightKind,
+    mut queue: VecDeque<(i32, i32, i32)>,
+) {
+    while let Some(position) = queue.pop_front() {
+        let level = world.light_at(kind, position);
+
+        if level <= 1 {
+            continue;
+        }
+
+        for (side, neighbour) in neighbours(position) {
+            let passes = world.block_at(neighbour).map_or(false, |block| registry.is_transparent(block));
+            let light = passed_on(kind, side, level);
+
+            if passes && world.light_at(kind, neighbour) < light {
+                world.set_light_at(kind, neighbour, light);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+}
+
+// Darkens everything that was lit through the queued positions, given with the light they had
+// (their own light must already be set). The darkened area is then lit again from its brighter
+// borders and the emitting blocks in it.
+pub fn remove_light(
+    world: &mut impl LightWorld,
+    registry: &BlockRegistry,
+    kind: LightKind,
+    mut queue: VecDeque<((i32, i32, i32), u8)>,
+) {
+    let mut refill = VecDeque::new();
+
+    while let Some((position, level)) = queue.pop_front() {
+        for (side, neighbour) in neighbours(position) {
+            let light = world.light_at(kind, neighbour);
+
+            if light == 0 {
+                continue;
+            }
+
+            if light < level || (light == MAX_LIGHT && passed_on(kind, side, level) == MAX_LIGHT) {
+                world.set_light_at(kind, neighbour, 0);
+                queue.push_back((neighbour, light));
+
+                let emission = match (kind, world.block_at(neighbour)) {
+                    (LightKind::Block, Some(block)) => registry.get(block).light_emission,
+                    _ => 0,
+                };
+
+                if emission > 0 {
+                    world.set_light_at(kind, neighbour, emission);
+                    refill.push_back(neighbour);
+                }
+            } else {
+                // Lit from somewhere else
+                refill.push_back(neighbour);
+            }
+        }
+    }
+
+    spread_light(world, registry, kind, refill);
+}
@@ -5,8 +5,26 @@ pub mod shader;
 pub mod util;
 pub mod chunk;
 pub mod chunk_manager;
+pub mod chunk_mesh;
+pub mod chunk_buffer;
 pub mod raycast;
 pub mod block_texture_sides;
+pub mod block_storage;
+pub mod block_registry;
+pub mod block_model;
+pub mod world_storage;
+pub mod nbt;
+pub mod anvil;
+pub mod world_gen;
+pub mod biome;
+pub mod structure;
+pub mod heightmap;
+pub mod frustum;
+pub mod light;
+pub mod visibility;
+pub mod map;
+pub mod mesher;
+pub mod jobs;
 pub mod aabb;
 
 pub mod texture;
@@ -18,7 +36,15 @@ pub mod ecs;
 pub mod collisions;
 
 use crate::chunk::{BlockID};
-use crate::chunk_manager::ChunkManager;
+use crate::block_registry::BlockRegistry;
+use crate::world_storage::{LevelInfo, WorldStorage};
+use crate::anvil::BlockMapping;
+use crate::world_gen::{load_ores, SimplexGenerator, WorldGenerator};
+use crate::jobs::JobSystem;
+use crate::heightmap::HeightmapKind;
+use crate::map::{average_color, MiniMap};
+use crate::chunk_manager::{ChunkManager, StreamingConfig};
+use crate::frustum::Frustum;
 use crate::renderer::{QuadProps, Renderer};
 use crate::shader::{ShaderPart, ShaderProgram};
 use crate::debugging::*;
@@ -37,6 +63,7 @@ use std::ffi::CString;
 use std::os::raw::c_void;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::sync::Arc;
 use crate::block_texture_sides::BlockFaces;
 use crate::aabb::AABB;
 use crate::collisions::player_collision_detection;
@@ -179,7 +206,15 @@ fn main() {
     gl_call!(gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA));
     gl_call!(gl::Viewport(0, 0, 800, 800));
 
-    let mut player = Player::new_at_position(vec3(0.0, 30.0, 0.0));
+    let storage = Arc::new(WorldStorage::open("world").expect("Failed to open the world directory"));
+    let (mut level, new_world) = match storage.load_level() {
+        Ok(Some(level)) => (level, false),
+        // The spawn height of a new world is found once the ground there is generated
+        Ok(None) => (LevelInfo { seed: 1296, spawn: [0.0, 30.0, 0.0] }, true),
+        Err(err) => panic!("Failed to read world/level.toml: {err}"),
+    };
+
+    let mut player = Player::new_at_position(vec3(level.spawn[0], level.spawn[1], level.spawn[2]));
 
 
     let mut renderer = Renderer::new(100_000); // _: 쉼표 느낌
@@ -187,25 +222,21 @@ fn main() {
     let vert = ShaderPart::from_vert_source(&CString::new(include_str!("shaders/diffuse.vert")).unwrap()).unwrap();
     let frag = ShaderPart::from_frag_source(&CString::new(include_str!("shaders/diffuse.frag")).unwrap()).unwrap();
     let mut program = ShaderProgram::from_shaders(vert, frag).unwrap();
+    let vert = ShaderPart::from_vert_source(&CString::new(include_str!("shaders/vert.vert")).unwrap()).unwrap();
+    let frag = ShaderPart::from_frag_source(&CString::new(include_str!("shaders/frag.frag")).unwrap()).unwrap();
+    let mut map_program = ShaderProgram::from_shaders(vert, frag).unwrap();
+    // Block types and their textures come from blocks.toml
+    let registry = match BlockRegistry::load("blocks.toml") {
+        Ok(registry) => Arc::new(registry),
+        Err(err) => panic!("Failed to load block definitions: {err}"),
+    };
+    let placeable_blocks: Vec<BlockID> = ["debug2", "glowstone", "ice", "stone_slab", "cobblestone_stairs", "oak_fence", "torch"]
+        .iter()
+        .map(|&name| registry.by_name(name).unwrap_or_else(|| panic!("Block '{name}' is not registered")))
+        .collect();
+    let mut place_block = placeable_blocks[0];
+
     // Generate texture atlas
-    let mut texture_map: HashMap<BlockID, BlockFaces<&str>> = HashMap::new();
-    texture_map.insert(BlockID::Dirt, BlockFaces::All("blocks/dirt.png"));
-    texture_map.insert(BlockID::GrassBlock, BlockFaces::Sides {
-        sides: "blocks/grass_block_side.png",
-        top: "blocks/grass_block_top.png",
-        bottom: "blocks/dirt.png",
-    });
-    texture_map.insert(BlockID::Cobblestone, BlockFaces::All("blocks/cobblestone.png"));
-    texture_map.insert(BlockID::Obsidian, BlockFaces::All("blocks/obsidian.png"));
-    texture_map.insert(BlockID::OakLog, BlockFaces::Sides{
-        sides: "blocks/oak_log.png",
-        top: "blocks/oak_log_top.png",
-        bottom: "blocks/oak_log_top.png"
-    });
-    texture_map.insert(BlockID::OakLeaves, BlockFaces::All("blocks/oak_leaves.png"));
-    texture_map.insert(BlockID::Debug, BlockFaces::All("blocks/debug.png"));
-    texture_map.insert(BlockID::Debug2, BlockFaces::All("blocks/debug2.png"));
-    
 
     let mut atlas = 0;
     gl_call!(gl::CreateTextures(gl::TEXTURE_2D, 1, &mut atlas));
@@ -222,6 +253,8 @@ fn main() {
     gl_call!(gl::TextureStorage2D(atlas, 1, gl::RGBA8, 1024, 1024,));
 
     let mut uv_map = HashMap::<BlockID, BlockFaces<UVCoords>>::new();
+    // Color of the top face of each block, for the map
+    let mut map_colors = HashMap::<BlockID, [u8; 3]>::new();
     let mut x = 0;
     let mut y = 0;
 
@@ -271,10 +304,16 @@ fn main() {
         (dest_x / 1024.0, dest_y/1024.0, (dest_x + 16.0) / 1024.0, (dest_y + 16.0) / 1024.0)
     };
 
-    for (block, faces) in texture_map {
+    for (block, definition) in registry.iter() {
+        let faces = match &definition.textures {
+            Some(faces) => faces,
+            None => continue,
+        };
+
         match faces {
             BlockFaces::All(all) => {
                 let mut img = load_image(all);
+                map_colors.insert(block, average_color(&img));
                 let uv = blit_image(&mut img);
                 uv_map.insert(block, BlockFaces::All(uv));
             }
@@ -283,6 +322,7 @@ fn main() {
                 let uv_sides = blit_image(&mut img);
 
                 let mut img = load_image(top);
+                map_colors.insert(block, average_color(&img));
                 let uv_top = blit_image(&mut img);
 
                 let mut img = load_image(bottom);
@@ -296,6 +336,7 @@ fn main() {
             }
             BlockFaces::Each { top, bottom, front, back, left, right } => {
                 let mut img = load_image(top);
+                map_colors.insert(block, average_color(&img));
                 let uv_top = blit_image(&mut img);
 
                 let mut img = load_image(bottom);
@@ -330,15 +371,60 @@ fn main() {
         }
     }
 
+    // Shared with the mesh workers from here on
+    let uv_map = Arc::new(uv_map);
+
     gl_call!(gl::ActiveTexture(gl::TEXTURE0 + 0));
     gl_call!(gl::BindTexture(gl::TEXTURE_2D, atlas));
 
-    let mut chunk_manager = ChunkManager::new();
+    let mut minimap = MiniMap::new(128, map_colors);
+    let mut show_map = false;
+
+    // `r-minecraft --import <dir>` shows the Minecraft region files (.mca) of <dir> instead of our world
+    let args: Vec<String> = std::env::args().collect();
+    let import_dir = args.iter().position(|arg| arg == "--import").and_then(|i| args.get(i + 1));
+    // `r-minecraft --stats` prints the mesh and block memory use on exit
+    let print_stats = args.iter().any(|arg| arg == "--stats");
+
+    let ores = match load_ores("ores.toml", &registry) {
+        Ok(ores) => ores,
+        Err(err) => panic!("Failed to load ore definitions: {err}"),
+    };
+    let generator: Arc<dyn WorldGenerator> = Arc::new(SimplexGenerator::new(level.seed, &registry, &ores));
+    let mut chunk_manager = ChunkManager::new(registry.clone(), generator.clone());
     // chunk_manager.preload_some_chunks();
-    chunk_manager.simplex();
+    let streaming = StreamingConfig::default();
+    if let Err(err) = streaming.validate() {
+        panic!("Invalid streaming config: {err}");
+    }
+
+    // Keep one core for the render loop
+    let worker_threads = std::thread::available_parallelism().map_or(1, |n| n.get().saturating_sub(1));
+    // Imported maps are only viewed, the workers never touch our world then
+    let worker_storage = import_dir.is_none().then(|| storage.clone());
+    let mut jobs = JobSystem::new(worker_threads, generator.clone(), registry.clone(), uv_map.clone(), worker_storage);
+
+    if import_dir.is_none() {
+        chunk_manager.pending_blocks = match storage.load_pending() {
+            Ok(pending) => pending,
+            Err(err) => panic!("Failed to read world/pending.dat: {err}"),
+        };
+    }
+
+    if let Some(dir) = import_dir {
+        let mapping = BlockMapping::from_registry(&registry, registry.by_name("debug"));
+
+        match anvil::import_directory(std::path::Path::new(dir), &mapping, &mut chunk_manager) {
+            Ok(n) => println!("Imported {n} chunks from {dir}"),
+            Err(err) => panic!("Failed to import {dir}: {err}"),
+        }
+    }
 
     let mut input_cache = InputCache::default();
     let mut prev_cursor_pos = (0.0, 0.0);
+    let mut current_biome = None;
+    let mut frames_since_title = 0;
+    let mut needs_spawn = new_world && import_dir.is_none();
 
 
     while !window.should_close() {
@@ -366,24 +452,35 @@ fn main() {
                         player.velocity.y = 1.0;
                     }
                 }
+                glfw::WindowEvent::Key(Key::M, _, Action::Press, _) => {
+                    show_map = !show_map;
+                }
+                // Cycle through the blocks to place: debug blocks, light sources, translucent ice, block models
+                glfw::WindowEvent::Key(Key::L, _, Action::Press, _) => {
+                    let current = placeable_blocks.iter().position(|&block| block == place_block).unwrap_or(0);
+                    place_block = placeable_blocks[(current + 1) % placeable_blocks.len()];
+                }
                 glfw::WindowEvent::Key(key, _, action, _) => {
                     input_cache.key_states.insert(key, action);
                 }
                 glfw::WindowEvent::MouseButton(button, Action::Press, _) => {
                     let forward = forward(&player.rotation);
                     let get_voxel = |x: i32, y: i32, z: i32| {
-                        chunk_manager.get_block(x, y, z).filter(|&block| block!= BlockID::Air).and_then(|_| Some((x, y, z)))
+                        chunk_manager.get_block(x, y, z).filter(|&block| !block.is_air()).and_then(|block| Some(((x, y, z), block)))
                     };
 
                     let hit =
                         raycast::raycast(&get_voxel, &player.get_camera_position(), &forward.normalize(), 400.0);
 
-                    if let Some(((x, y, z), normal)) = hit {
+                    if let Some((((x, y, z), block), normal)) = hit {
                         if button == MouseButton::Button1 {
-                            chunk_manager.set_block(x, y, z, BlockID::Air)
+                            // Negative hardness: unbreakable
+                            if registry.get(block).hardness >= 0.0 {
+                                chunk_manager.set_block(x, y, z, BlockID::AIR)
+                            }
                         } else if button == MouseButton::Button2 {
                             let near = IVec3::new(x, y, z) + normal;
-                            chunk_manager.set_block(near.x, near.y, near.z, BlockID::Debug2);
+                            chunk_manager.set_block(near.x, near.y, near.z, place_block);
                             println!("Put block at {} {} {}", near.x, near.y, near.z);
                         }
 
@@ -434,7 +531,21 @@ fn main() {
         let view_matrix = nalgebra_glm::look_at(&camera_position, &(camera_position + direction), &Vector3::y());
         let projection_matrix = nalgebra_glm::perspective(1.0, pi::<f32>() / 2.0, 0.1, 1000.0);
 
-        chunk_manager.rebuild_dirty_chunks(&uv_map);
+        // Imported maps are loaded up front, only our own world streams in
+        let camera_chunk = ChunkManager::chunk_coords_of(&camera_position);
+        jobs.set_camera(camera_chunk);
+
+        if import_dir.is_none() {
+            chunk_manager.update_streaming(&player.position, &streaming, &mut jobs);
+        }
+
+        // Biome the player stands in, for the window title
+        if import_dir.is_none() {
+            current_biome = Some(chunk_manager.biome_at(player.position.x.floor() as i32, player.position.z.floor() as i32));
+        }
+
+        chunk_manager.receive_jobs(&mut jobs, camera_chunk, &streaming);
+        chunk_manager.rebuild_dirty_chunks(&mut jobs, camera_chunk, &streaming);
 
         program.use_program();
         program.set_uniform_matrix4fv("view", view_matrix.as_ptr());
@@ -448,12 +559,51 @@ fn main() {
         gl_call!(gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT));
 // 화면이 front(보여지는거)와 back buffer(갱신한 윈도우)가 있는데 그걸 바꿔치기한다.
 
-        chunk_manager.render_loaded_chunks(&mut program);
+        let frustum = Frustum::from_matrix(&(projection_matrix * view_matrix));
+        let render_stats = chunk_manager.render_loaded_chunks(&frustum, &camera_position);
+
+        // Biome and chunk counts, refreshed every 30 frames
+        frames_since_title += 1;
+
+        if frames_since_title >= 30 {
+            let biome = current_biome.map_or(String::new(), |biome| format!(" - {}", biome.name()));
+            window.set_title(&format!(
+                "{window_title}{biome} - {} chunks drawn, {} culled, {} occluded",
+                render_stats.drawn, render_stats.culled, render_stats.occluded
+            ));
+            frames_since_title = 0;
+        }
+
+        if show_map {
+            minimap.update(&chunk_manager, player.position.x.floor() as i32, player.position.z.floor() as i32);
+            minimap.draw(&mut renderer, &mut map_program);
+        }
 
-        player.acceleration.y = -0.02;
-        player.velocity += player.acceleration;
+        // Stand on the highest solid block once the whole column is there
+        if needs_spawn {
+            let (chunk_x, _, chunk_z) = ChunkManager::chunk_coords_of(&player.position);
 
-        player_collision_detection(&mut player, &chunk_manager);
+            if chunk_manager.is_column_loaded(chunk_x, chunk_z, &streaming) {
+                let (x, z) = (player.position.x.floor() as i32, player.position.z.floor() as i32);
+                let ground = chunk_manager.height_at(x, z, HeightmapKind::MotionBlocking).unwrap_or(0);
+                let rotation = player.rotation;
+
+                player = Player::new_at_position(vec3(player.position.x, ground as f32 + 1.0, player.position.z));
+                player.rotation = rotation;
+                level.spawn = [player.position.x, player.position.y, player.position.z];
+                needs_spawn = false;
+            }
+        }
+
+        // Wait for the ground under the player to be generated instead of falling through it
+        let player_chunk = ChunkManager::chunk_coords_of(&player.position);
+
+        if !needs_spawn && (import_dir.is_some() || chunk_manager.loaded_chunks.contains_key(&player_chunk)) {
+            player.acceleration.y = -0.02;
+            player.velocity += player.acceleration;
+
+            player_collision_detection(&mut player, &chunk_manager, &registry);
+        }
 
         player.velocity.x *= 0.9;
         player.velocity.z *= 0.9;
@@ -464,4 +614,36 @@ fn main() {
         window.swap_buffers();
 
     }
+
+    if print_stats {
+        let (drawn, unmerged) = chunk_manager.mesh_vertex_counts();
+        println!("Chunk meshes: {drawn} vertices ({unmerged} without greedy meshing)");
+
+        let (indexed_bytes, unindexed_bytes) = chunk_manager.mesh_memory_usage();
+        println!(
+            "Chunk mesh VRAM: {} KiB with indexed quads ({} KiB without, {} KiB saved)",
+            indexed_bytes / 1024,
+            unindexed_bytes / 1024,
+            (unindexed_bytes as i64 - indexed_bytes as i64) / 1024
+        );
+        println!("Shared chunk buffer: {} KiB allocated", chunk_manager.chunk_buffer_size() / 1024);
+
+        let (palette_bytes, flat_bytes) = chunk_manager.block_memory_usage();
+        println!("Block storage: {} KiB (flat arrays would use {} KiB)", palette_bytes / 1024, flat_bytes / 1024);
+    }
+
+    // Stops the workers, so none is still writing a region while the rest is saved
+    drop(jobs);
+
+    // Imported maps are only viewed, they must not overwrite our world
+    if import_dir.is_none() {
+        let saved = storage
+            .save_level(&level)
+            .and_then(|_| chunk_manager.save_all(&storage))
+            .and_then(|_| storage.save_pending(&chunk_manager.pending_blocks));
+
+        if let Err(err) = saved {
+            println!("Failed to save the world: {err}");
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use crate::chunk::BlockID;
+use crate::chunk_manager::ChunkManager;
+use crate::heightmap::HeightmapKind;
+use crate::renderer::{QuadProps, Renderer};
+use crate::shader::ShaderProgram;
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::os::raw::c_void;
+
+// Redraw at least this often, so newly generated chunks show up while standing still
+const REDRAW_INTERVAL: u32 = 30;
+
+// Mean color of the opaque pixels of a texture, used as the block's map color
+pub fn average_color(img: &DynamicImage) -> [u8; 3] {
+    let (mut sum, mut count) = ([0u32; 3], 0);
+
+    for pixel in img.to_rgba8().pixels() {
+        if pixel[3] > 0 {
+            for i in 0..3 {
+                sum[i] += pixel[i] as u32;
+            }
+            count += 1;
+        }
+    }
+
+    sum.map(|c| (c / count.max(1)) as u8)
+}
+
+// Top-down map of the area around the player, one pixel per block, read from the surface heightmap.
+// North (-z) is up.
+pub struct MiniMap {
+    texture: u32,
+    // Blocks across
+    size: u32,
+    colors: HashMap<BlockID, [u8; 3]>,
+    pixels: Vec<u8>,
+    center: Option<(i32, i32)>,
+    frames_since_redraw: u32,
+}
+
+impl MiniMap {
+    pub fn new(size: u32, colors: HashMap<BlockID, [u8; 3]>) -> MiniMap {
+        let mut texture = 0;
+        gl_call!(gl::CreateTextures(gl::TEXTURE_2D, 1, &mut texture));
+        gl_call!(gl::TextureParameteri(texture, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32));
+        gl_call!(gl::TextureParameteri(texture, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32));
+        gl_call!(gl::TextureStorage2D(texture, 1, gl::RGBA8, size as i32, size as i32));
+
+        MiniMap {
+            texture,
+            size,
+            colors,
+            pixels: vec![0; (size * size * 4) as usize],
+            center: None,
+            frames_since_redraw: 0,
+        }
+    }
+
+    // Redraws the map around (x, z) if the player moved or the last redraw is old
+    pub fn update(&mut self, chunk_manager: &ChunkManager, x: i32, z: i32) {
+        self.frames_since_redraw += 1;
+
+        if self.center == Some((x, z)) && self.frames_since_redraw < REDRAW_INTERVAL {
+            return;
+        }
+
+        self.center = Some((x, z));
+        self.frames_since_redraw = 0;
+
+        let half = self.size as i32 / 2;
+
+        for row in 0..self.size as i32 {
+            for column in 0..self.size as i32 {
+                // Texture rows go bottom to top
+                let (wx, wz) = (x - half + column, z + half - 1 - row);
+
+                let color = match chunk_manager.height_at(wx, wz, HeightmapKind::Surface) {
+                    Some(y) => {
+                        let block = chunk_manager.get_block(wx, y, wz).unwrap_or(BlockID::AIR);
+                        let [r, g, b] = self.colors.get(&block).copied().unwrap_or([128, 128, 128]);
+
+                        // Slopes facing north are lit, the ones facing south are shaded
+                        let shade = match chunk_manager.height_at(wx, wz - 1, HeightmapKind::Surface) {
+                            Some(north) if north < y => 1.15,
+                            Some(north) if north > y => 0.75,
+                            _ => 1.0,
+                        };
+                        let shaded = |c: u8| (c as f32 * shade).min(255.0) as u8;
+
+                        [shaded(r), shaded(g), shaded(b), 255]
+                    }
+                    None => [0, 0, 0, 160],
+                };
+
+                let i = ((row * self.size as i32 + column) * 4) as usize;
+                self.pixels[i..i + 4].copy_from_slice(&color);
+            }
+        }
+
+        // Player marker
+        for (dx, dy) in [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let i = (((half + dy) * self.size as i32 + half + dx) * 4) as usize;
+            self.pixels[i..i + 4].copy_from_slice(&[255, 0, 0, 255]);
+        }
+
+        gl_call!(gl::TextureSubImage2D(
+            self.texture,
+            0,
+            0,
+            0,
+            self.size as i32,
+            self.size as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            self.pixels.as_ptr() as *const c_void
+        ));
+    }
+
+    // Draws the map in the top right corner of the screen
+    pub fn draw(&self, renderer: &mut Renderer, program: &mut ShaderProgram) {
+        renderer.begin_batch();
+        renderer.submit_quad(QuadProps {
+            position: (0.5, 0.5, 0.0),
+            size: (0.45, 0.45),
+            texture_id: self.texture,
+            texture_coords: (0.0, 0.0, 1.0, 1.0),
+        });
+
+        program.use_program();
+
+        gl_call!(gl::Disable(gl::DEPTH_TEST));
+        renderer.end_batch(program);
+        gl_call!(gl::Enable(gl::DEPTH_TEST));
+    }
+}
+
+impl Drop for MiniMap {
+    fn drop(&mut self) {
+        gl_call!(gl::DeleteTextures(1, &self.texture));
+    }
+}
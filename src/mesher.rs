@@ -0,0 +1,452 @@
+use crate::block_model::ModelQuad;
+use crate::block_registry::BlockRegistry;
+use crate::block_texture_sides::{get_uv_every_side, BlockFaces};
+use crate::chunk::{BlockID, ChunkData};
+use crate::chunk_manager::CHUNK_SIZE;
+use crate::light::MAX_LIGHT;
+use crate::shapes::{vertex_position, write_face_to_ptr, write_model_face_to_ptr, CornerShading, FACE_CORNERS, VERTEX_WORDS};
+use crate::visibility::{opposite, VisibilityGraph};
+use crate::UVCoords;
+use nalgebra_glm::Vec3;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub type UVMap = HashMap<BlockID, BlockFaces<UVCoords>>;
+
+// Tiles per row of the texture atlas (1024 x 1024 pixels, 16 x 16 per tile).
+// diffuse.vert has the same constant.
+pub const ATLAS_TILES: u32 = 64;
+
+// Offsets of the six neighbours, in the order of Sides
+pub const NEIGHBOUR_OFFSETS: [(i32, i32, i32); 6] = [
+    (1, 0, 0),  // right
+    (-1, 0, 0), // left
+    (0, 1, 0),  // top
+    (0, -1, 0), // bottom
+    (0, 0, 1),  // front
+    (0, 0, -1), // back
+];
+
+// Chunk size with a one block border on each side
+const PADDED: i32 = CHUNK_SIZE as i32 + 2;
+
+// Immutable copy of a chunk and a one block border around it taken from its 26 neighbours,
+// so it can be meshed on another thread while the world keeps changing.
+pub struct ChunkSnapshot {
+    // None: the neighbour isn't loaded
+    blocks: Vec<Option<BlockID>>,
+    // Sky light << 4 | block light
+    light: Vec<u8>,
+}
+
+impl ChunkSnapshot {
+    pub fn capture(chunks: &HashMap<(i32, i32, i32), ChunkData>, (cx, cy, cz): (i32, i32, i32)) -> ChunkSnapshot {
+        let size = CHUNK_SIZE as i32;
+        let volume = (PADDED * PADDED * PADDED) as usize;
+        let mut snapshot = ChunkSnapshot {
+            blocks: Vec::with_capacity(volume),
+            light: Vec::with_capacity(volume),
+        };
+
+        for y in -1..=size {
+            for z in -1..=size {
+                for x in -1..=size {
+                    let coords = (cx + x.div_euclid(size), cy + y.div_euclid(size), cz + z.div_euclid(size));
+                    let (bx, by, bz) = (x.rem_euclid(size) as u32, y.rem_euclid(size) as u32, z.rem_euclid(size) as u32);
+
+                    match chunks.get(&coords) {
+                        Some(chunk) => {
+                            snapshot.blocks.push(Some(chunk.get_block(bx, by, bz)));
+                            snapshot.light.push(chunk.get_packed_light(bx, by, bz));
+                        }
+                        None => {
+                            snapshot.blocks.push(None);
+                            snapshot.light.push(MAX_LIGHT << 4);
+                        }
+                    }
+                }
+            }
+        }
+
+        snapshot
+    }
+
+    // Chunk-local coordinates, at most one block outside the chunk
+    #[inline]
+    fn index(x: i32, y: i32, z: i32) -> usize {
+        ((y + 1) * PADDED * PADDED + (z + 1) * PADDED + (x + 1)) as usize
+    }
+
+    fn get_block(&self, x: i32, y: i32, z: i32) -> Option<BlockID> {
+        self.blocks[ChunkSnapshot::index(x, y, z)]
+    }
+
+    // Unloaded blocks let light through, like air
+    fn is_opaque(&self, registry: &BlockRegistry, x: i32, y: i32, z: i32) -> bool {
+        self.get_block(x, y, z).map_or(false, |block| !registry.is_transparent(block))
+    }
+
+    // Whether the block at (x, y, z) is loaded and covers its face on `side`
+    fn hides_face(&self, registry: &BlockRegistry, (x, y, z): (i32, i32, i32), side: usize) -> bool {
+        self.get_block(x, y, z).map_or(false, |block| registry.hides_face(block, side))
+    }
+
+    // A face is visible unless the neighbouring block is loaded and covers it. Translucent blocks also
+    // hide the faces between two blocks of the same kind, like the inside of a pond of ice.
+    fn is_face_visible(&self, registry: &BlockRegistry, (x, y, z): (i32, i32, i32), side: usize) -> bool {
+        let (dx, dy, dz) = NEIGHBOUR_OFFSETS[side];
+        let block = self.get_block(x, y, z);
+
+        if block.map_or(false, |block| registry.is_translucent(block)) && self.get_block(x + dx, y + dy, z + dz) == block {
+            return false;
+        }
+        !self.hides_face(registry, (x + dx, y + dy, z + dz), opposite(side))
+    }
+
+    // Ambient occlusion and smooth light of the four corners of a face. Each corner looks at the
+    // block in front of the face and the three blocks around the corner in that same layer.
+    fn face_shading(&self, registry: &BlockRegistry, (x, y, z): (i32, i32, i32), side: usize) -> [CornerShading; 4] {
+        let (nx, ny, nz) = NEIGHBOUR_OFFSETS[side];
+        let front = (x + nx, y + ny, z + nz);
+
+        FACE_CORNERS[side].map(|(cx, cy, cz)| {
+            // Towards the corner along the two axes of the face, 0 along the normal
+            let towards = |c: u32, n: i32| if n != 0 { 0 } else if c == 1 { 1 } else { -1 };
+            let (tx, ty, tz) = (towards(cx, nx), towards(cy, ny), towards(cz, nz));
+
+            // Split (tx, ty, tz) into its two non-zero axes
+            let (first, second) = match (tx, ty, tz) {
+                (0, _, _) => ((0, ty, 0), (0, 0, tz)),
+                (_, 0, _) => ((tx, 0, 0), (0, 0, tz)),
+                _ => ((tx, 0, 0), (0, ty, 0)),
+            };
+
+            let at = |(dx, dy, dz): (i32, i32, i32)| (front.0 + dx, front.1 + dy, front.2 + dz);
+            let side1 = at(first);
+            let side2 = at(second);
+            let corner = at((tx, ty, tz));
+
+            let opaque = |(x, y, z): (i32, i32, i32)| self.is_opaque(registry, x, y, z);
+            let (side1_opaque, side2_opaque) = (opaque(side1), opaque(side2));
+
+            // Both sides closed: the corner block can't be seen from here
+            let corner_opaque = (side1_opaque && side2_opaque) || opaque(corner);
+            let ao = if side1_opaque && side2_opaque {
+                0
+            } else {
+                3 - side1_opaque as u8 - side2_opaque as u8 - corner_opaque as u8
+            };
+
+            // Average light of the open blocks
+            let (mut sky, mut block, mut count) = (0.0, 0.0, 0.0);
+            for (position, is_opaque) in [(front, false), (side1, side1_opaque), (side2, side2_opaque), (corner, corner_opaque)] {
+                if !is_opaque {
+                    let packed = self.light[ChunkSnapshot::index(position.0, position.1, position.2)];
+                    sky += (packed >> 4) as f32;
+                    block += (packed & 0x0F) as f32;
+                    count += 1.0;
+                }
+            }
+
+            [sky / count, block / count, ao as f32]
+        })
+    }
+
+    // Blocks with a model are drawn apart, see model_faces
+    fn block_face(&self, registry: &BlockRegistry, uv_map: &UVMap, position: (i32, i32, i32), side: usize) -> Option<Face> {
+        let block = match self.get_block(position.0, position.1, position.2) {
+            Some(block) if !block.is_air() && uv_map.contains_key(&block) && registry.model(block).is_none() => block,
+            _ => return None,
+        };
+
+        if !self.is_face_visible(registry, position, side) {
+            return None;
+        }
+        Some((block, self.face_shading(registry, position, side)))
+    }
+
+    // Block drawn for a cell of `scale` blocks per axis: the highest one, so grass stays on top.
+    // A cell is solid as soon as it has a block in it, which keeps it around every block. Models
+    // without a full face (torches, plants...) are too small to be seen from there.
+    fn cell_block(&self, registry: &BlockRegistry, uv_map: &UVMap, (cx, cy, cz): (i32, i32, i32), scale: i32) -> Option<BlockID> {
+        let counts = |block: BlockID| registry.model(block).map_or(true, |model| model.has_full_face());
+
+        for y in (cy * scale..(cy + 1) * scale).rev() {
+            for z in cz * scale..(cz + 1) * scale {
+                for x in cx * scale..(cx + 1) * scale {
+                    match self.get_block(x, y, z) {
+                        Some(block) if !block.is_air() && uv_map.contains_key(&block) && counts(block) => return Some(block),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Faces of the block models in the chunk that aren't culled, with the block's position and
+    // light (sky, block)
+    fn model_faces<'a>(&self, registry: &'a BlockRegistry, uv_map: &UVMap) -> Vec<ModelFace<'a>> {
+        let size = CHUNK_SIZE as i32;
+        let mut faces = Vec::new();
+
+        for y in 0..size {
+            for z in 0..size {
+                for x in 0..size {
+                    let block = match self.get_block(x, y, z) {
+                        Some(block) if !block.is_air() && uv_map.contains_key(&block) => block,
+                        _ => continue,
+                    };
+                    let model = match registry.model(block) {
+                        Some(model) => model,
+                        None => continue,
+                    };
+
+                    let packed = self.light[ChunkSnapshot::index(x, y, z)];
+                    let light = (packed >> 4, packed & 0x0F);
+
+                    for quad in &model.quads {
+                        if quad.cullface.map_or(true, |side| self.is_face_visible(registry, (x, y, z), side)) {
+                            faces.push(((x as u32, y as u32, z as u32), block, quad, light));
+                        }
+                    }
+                }
+            }
+        }
+
+        faces
+    }
+
+    // Blocks of the cell next to `cell` on `side`. Outside the chunk only the border layer of the
+    // snapshot is known, so those are clamped to it.
+    fn blocks_in_front(cell: (i32, i32, i32), side: usize, scale: i32) -> Vec<(i32, i32, i32)> {
+        let (nx, ny, nz) = NEIGHBOUR_OFFSETS[side];
+        let (fx, fy, fz) = ((cell.0 + nx) * scale, (cell.1 + ny) * scale, (cell.2 + nz) * scale);
+        let clamp = |v: i32| v.clamp(-1, CHUNK_SIZE as i32);
+
+        let mut blocks = Vec::with_capacity((scale * scale * scale) as usize);
+        for y in fy..fy + scale {
+            for z in fz..fz + scale {
+                for x in fx..fx + scale {
+                    blocks.push((clamp(x), clamp(y), clamp(z)));
+                }
+            }
+        }
+        blocks
+    }
+
+    // Face of a cell for level of detail meshes. Inside the chunk it's hidden by a solid cell. On
+    // the border it's only hidden by a fully covering layer of the neighbouring chunk, whatever level
+    // that chunk is drawn at: since cells cover every block in them, these border faces close the
+    // seams to neighbours drawn at other levels like skirts. Lit by the brightest block in front,
+    // without ambient occlusion.
+    fn cell_face(&self, registry: &BlockRegistry, uv_map: &UVMap, cell: (i32, i32, i32), side: usize, scale: i32) -> Option<Face> {
+        let block = self.cell_block(registry, uv_map, cell, scale)?;
+
+        let (nx, ny, nz) = NEIGHBOUR_OFFSETS[side];
+        let neighbour = (cell.0 + nx, cell.1 + ny, cell.2 + nz);
+        let cells = CHUNK_SIZE as i32 / scale;
+        let in_front = ChunkSnapshot::blocks_in_front(cell, side, scale);
+
+        let hidden = if [neighbour.0, neighbour.1, neighbour.2].iter().all(|v| (0..cells).contains(v)) {
+            self.cell_block(registry, uv_map, neighbour, scale).is_some()
+        } else {
+            in_front.iter().all(|&position| self.hides_face(registry, position, opposite(side)))
+        };
+
+        if hidden {
+            return None;
+        }
+
+        let (sky, block_light) = in_front.iter().fold((0, 0), |(sky, block), &(x, y, z)| {
+            let packed = self.light[ChunkSnapshot::index(x, y, z)];
+            (sky.max(packed >> 4), block.max(packed & 0x0F))
+        });
+
+        Some((block, [[sky as f32, block_light as f32, 3.0]; 4]))
+    }
+}
+
+// Index of the atlas tile starting at (u0, v0)
+fn tile_index(uv: UVCoords) -> u32 {
+    let (column, row) = ((uv.0 * ATLAS_TILES as f32).round() as u32, (uv.1 * ATLAS_TILES as f32).round() as u32);
+    row * ATLAS_TILES + column
+}
+
+// Packed vertices of a chunk (see shapes::VERTEX_WORDS) with its translucent faces apart, how
+// many vertices there would be with one quad per block face, which faces of the chunk see each
+// other for cave culling and the level of detail it was built at
+pub struct MeshData {
+    pub vertices: Vec<u32>,
+    pub translucent_vertices: Vec<u32>,
+    pub unmerged_vertices: u32,
+    pub visibility: VisibilityGraph,
+    pub lod: u32,
+}
+
+// Visible face of a block or cell, before merging
+type Face = (BlockID, [CornerShading; 4]);
+
+// Position, block, face and (sky, block) light of a block model face
+type ModelFace<'a> = ((u32, u32, u32), BlockID, &'a ModelQuad, (u8, u8));
+
+// Coarsest level of detail: cells of 8 x 8 x 8 blocks
+pub const MAX_LOD: u32 = 3;
+
+// Vertices (chunk-local, packed) of every visible face of the chunk. Neighbouring faces of the same
+// block type and shading in the same plane are merged into larger quads (greedy meshing).
+//
+// Above level of detail 0 the chunk is downsampled into cells of 2^lod blocks per axis, see
+// ChunkSnapshot::cell_face.
+pub fn build_mesh(snapshot: &ChunkSnapshot, registry: &BlockRegistry, uv_map: &UVMap, lod: u32) -> MeshData {
+    let scale = 1 << lod;
+    let size = CHUNK_SIZE as i32 / scale;
+    let face_at = |position: (i32, i32, i32), side: usize| {
+        if lod == 0 {
+            snapshot.block_face(registry, uv_map, position, side)
+        } else {
+            snapshot.cell_face(registry, uv_map, position, side, scale)
+        }
+    };
+
+    // (position, size in blocks, side, block, shading)
+    let mut quads = Vec::new();
+    let mut n_faces = 0;
+
+    for side in 0..6 {
+        // Axis of the face normal and the two axes of the plane: x, y, z = 0, 1, 2
+        let normal_axis = side / 2;
+        let (a_axis, b_axis) = ((normal_axis + 1) % 3, (normal_axis + 2) % 3);
+
+        for layer in 0..size {
+            let mut mask: Vec<Option<Face>> = vec![None; (size * size) as usize];
+
+            for b in 0..size {
+                for a in 0..size {
+                    let mut position = [0; 3];
+                    position[normal_axis] = layer;
+                    position[a_axis] = a;
+                    position[b_axis] = b;
+
+                    if let Some(face) = face_at((position[0], position[1], position[2]), side) {
+                        mask[(b * size + a) as usize] = Some(face);
+                        n_faces += 1;
+                    }
+                }
+            }
+
+            for b in 0..size {
+                let mut a = 0;
+
+                while a < size {
+                    let face = match mask[(b * size + a) as usize] {
+                        Some(face) => face,
+                        None => {
+                            a += 1;
+                            continue;
+                        }
+                    };
+
+                    // Only faces shaded the same at every corner can be stretched
+                    let (block, shading) = face;
+                    let uniform = shading.iter().all(|corner| *corner == shading[0]);
+                    let same = |a: i32, b: i32| mask[(b * size + a) as usize] == Some(face);
+
+                    let mut width = 1;
+                    while uniform && a + width < size && same(a + width, b) {
+                        width += 1;
+                    }
+
+                    let mut height = 1;
+                    while uniform && b + height < size && (a..a + width).all(|a| same(a, b + height)) {
+                        height += 1;
+                    }
+
+                    for b in b..b + height {
+                        for a in a..a + width {
+                            mask[(b * size + a) as usize] = None;
+                        }
+                    }
+
+                    // Back to blocks
+                    let (mut position, mut extent) = ([0; 3], [scale as u32; 3]);
+                    position[normal_axis] = (layer * scale) as u32;
+                    position[a_axis] = (a * scale) as u32;
+                    position[b_axis] = (b * scale) as u32;
+                    extent[a_axis] = (width * scale) as u32;
+                    extent[b_axis] = (height * scale) as u32;
+
+                    quads.push(((position[0], position[1], position[2]), (extent[0], extent[1], extent[2]), side, block, shading));
+                    a += width;
+                }
+            }
+        }
+    }
+
+    let model_faces = if lod == 0 { snapshot.model_faces(registry, uv_map) } else { Vec::new() };
+    n_faces += model_faces.len() as u32;
+
+    // 4 vertices per quad, allocated exactly
+    let n_translucent = quads.iter().filter(|quad| registry.is_translucent(quad.3)).count()
+        + model_faces.iter().filter(|face| registry.is_translucent(face.1)).count();
+    let n_quads = quads.len() + model_faces.len();
+    let mut vertices: Vec<u32> = Vec::with_capacity((n_quads - n_translucent) * 4 * VERTEX_WORDS);
+    let mut translucent_vertices: Vec<u32> = Vec::with_capacity(n_translucent * 4 * VERTEX_WORDS);
+
+    for (position, extent, side, block, shading) in quads {
+        let (front, back, top, bottom, left, right) = get_uv_every_side(uv_map[&block]);
+        let tile = tile_index([right, left, top, bottom, front, back][side]);
+
+        let vertices = if registry.is_translucent(block) { &mut translucent_vertices } else { &mut vertices };
+        let len = vertices.len();
+        let copied_vertices = unsafe { write_face_to_ptr(vertices.as_mut_ptr().add(len), position, extent, side, tile, shading) };
+
+        unsafe { vertices.set_len(len + copied_vertices as usize * VERTEX_WORDS) };
+    }
+
+    for (position, block, quad, light) in model_faces {
+        let (front, back, top, bottom, left, right) = get_uv_every_side(uv_map[&block]);
+        let tile = tile_index([right, left, top, bottom, front, back][quad.texture]);
+
+        let vertices = if registry.is_translucent(block) { &mut translucent_vertices } else { &mut vertices };
+        let len = vertices.len();
+        let copied_vertices = unsafe { write_model_face_to_ptr(vertices.as_mut_ptr().add(len), position, quad, tile, light) };
+
+        unsafe { vertices.set_len(len + copied_vertices as usize * VERTEX_WORDS) };
+    }
+
+    MeshData {
+        vertices,
+        translucent_vertices,
+        unmerged_vertices: n_faces * 4,
+        visibility: VisibilityGraph::compute(|x, y, z| !snapshot.is_opaque(registry, x, y, z)),
+        lod,
+    }
+}
+
+// Reorders the quads of `vertices` from the farthest to the nearest to `eye` (chunk-local), so
+// blending them in order shows the near faces over the far ones
+pub fn sort_back_to_front(vertices: &mut [u32], eye: &Vec3) {
+    const QUAD_WORDS: usize = 4 * VERTEX_WORDS;
+
+    let distance = |quad: &[u32; QUAD_WORDS]| {
+        let center = quad.chunks(VERTEX_WORDS).fold(Vec3::zeros(), |sum, vertex| {
+            let (x, y, z) = vertex_position(vertex);
+            sum + Vec3::new(x, y, z)
+        }) / 4.0;
+        (center - eye).norm_squared()
+    };
+
+    let mut quads: Vec<(f32, [u32; QUAD_WORDS])> = vertices
+        .chunks(QUAD_WORDS)
+        .map(|quad| {
+            let quad = quad.try_into().unwrap();
+            (distance(&quad), quad)
+        })
+        .collect();
+    quads.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+    for (i, (_, quad)) in quads.iter().enumerate() {
+        vertices[i * QUAD_WORDS..(i + 1) * QUAD_WORDS].copy_from_slice(quad);
+    }
+}
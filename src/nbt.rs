@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+// Named Binary Tag, the big-endian format Minecraft stores chunks in.
+// https://minecraft.wiki/w/NBT_format
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+const TAG_END: u8 = 0;
+const TAG_COMPOUND: u8 = 10;
+
+// Deeply nested lists/compounds are only produced by broken or hostile files
+const MAX_DEPTH: usize = 512;
+
+impl Tag {
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_long_array(&self) -> Option<&[i64]> {
+        match self {
+            Tag::LongArray(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    // Any integer tag, widened
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(v) => Some(v as i64),
+            Tag::Short(v) => Some(v as i64),
+            Tag::Int(v) => Some(v as i64),
+            Tag::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+struct Reader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.bytes()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.bytes()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.bytes()?))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let len = self.i32()?;
+        usize::try_from(len).map_err(|_| invalid("negative length"))
+    }
+
+    // Java's modified UTF-8 only differs for NUL and supplementary characters,
+    // which never appear in block names
+    fn string(&mut self) -> io::Result<String> {
+        let len = u16::from_be_bytes(self.bytes()?) as usize;
+        let mut buf = vec![0u8; len];
+        self.input.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn payload(&mut self, tag_type: u8, depth: usize) -> io::Result<Tag> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("NBT nested too deeply"));
+        }
+
+        Ok(match tag_type {
+            1 => Tag::Byte(self.u8()? as i8),
+            2 => Tag::Short(self.i16()?),
+            3 => Tag::Int(self.i32()?),
+            4 => Tag::Long(self.i64()?),
+            5 => Tag::Float(f32::from_be_bytes(self.bytes()?)),
+            6 => Tag::Double(f64::from_be_bytes(self.bytes()?)),
+            7 => {
+                let len = self.len()?;
+                // Don't trust `len` for the allocation, a truncated file would claim gigabytes
+                let mut buf = Vec::new();
+                (&mut self.input).take(len as u64).read_to_end(&mut buf)?;
+                if buf.len() != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Tag::ByteArray(buf.into_iter().map(|b| b as i8).collect())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                let element_type = self.u8()?;
+                let len = self.len()?;
+                let mut list = Vec::new();
+                for _ in 0..len {
+                    list.push(self.payload(element_type, depth + 1)?);
+                }
+                Tag::List(list)
+            }
+            TAG_COMPOUND => {
+                let mut map = HashMap::new();
+                loop {
+                    let element_type = self.u8()?;
+                    if element_type == TAG_END {
+                        break;
+                    }
+                    let name = self.string()?;
+                    map.insert(name, self.payload(element_type, depth + 1)?);
+                }
+                Tag::Compound(map)
+            }
+            11 => {
+                let len = self.len()?;
+                let mut array = Vec::new();
+                for _ in 0..len {
+                    array.push(self.i32()?);
+                }
+                Tag::IntArray(array)
+            }
+            12 => {
+                let len = self.len()?;
+                let mut array = Vec::new();
+                for _ in 0..len {
+                    array.push(self.i64()?);
+                }
+                Tag::LongArray(array)
+            }
+            _ => return Err(invalid("unknown NBT tag type")),
+        })
+    }
+}
+
+// Reads an uncompressed NBT document: a single named compound
+pub fn read_nbt(input: impl Read) -> io::Result<(String, Tag)> {
+    let mut reader = Reader { input };
+
+    if reader.u8()? != TAG_COMPOUND {
+        return Err(invalid("NBT root is not a compound"));
+    }
+
+    let name = reader.string()?;
+    let root = reader.payload(TAG_COMPOUND, 0)?;
+
+    Ok((name, root))
+}
+
+// Writes a named root compound, the inverse of read_nbt. Only used to build test data.
+#[cfg(test)]
+pub fn write_nbt(name: &str, root: &Tag) -> Vec<u8> {
+    fn tag_type(tag: &Tag) -> u8 {
+        match tag {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => TAG_COMPOUND,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    fn payload(out: &mut Vec<u8>, tag: &Tag) {
+        match tag {
+            Tag::Byte(v) => out.push(*v as u8),
+            Tag::Short(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::Int(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::Long(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::Float(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::Double(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::ByteArray(array) => {
+                out.extend_from_slice(&(array.len() as i32).to_be_bytes());
+                out.extend(array.iter().map(|&b| b as u8));
+            }
+            Tag::String(s) => string(out, s),
+            Tag::List(list) => {
+                out.push(list.first().map_or(TAG_END, tag_type));
+                out.extend_from_slice(&(list.len() as i32).to_be_bytes());
+                for element in list {
+                    payload(out, element);
+                }
+            }
+            Tag::Compound(map) => {
+                for (name, element) in map {
+                    out.push(tag_type(element));
+                    string(out, name);
+                    payload(out, element);
+                }
+                out.push(TAG_END);
+            }
+            Tag::IntArray(array) => {
+                out.extend_from_slice(&(array.len() as i32).to_be_bytes());
+                for v in array {
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+            }
+            Tag::LongArray(array) => {
+                out.extend_from_slice(&(array.len() as i32).to_be_bytes());
+                for v in array {
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    let mut out = vec![TAG_COMPOUND];
+    string(&mut out, name);
+    payload(&mut out, root);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compound(entries: Vec<(&str, Tag)>) -> Tag {
+        Tag::Compound(entries.into_iter().map(|(name, tag)| (name.to_owned(), tag)).collect())
+    }
+
+    #[test]
+    fn reads_every_tag_type() {
+        let root = compound(vec![
+            ("byte", Tag::Byte(-5)),
+            ("short", Tag::Short(-300)),
+            ("int", Tag::Int(70_000)),
+            ("long", Tag::Long(-1 << 40)),
+            ("float", Tag::Float(1.5)),
+            ("double", Tag::Double(-2.25)),
+            ("bytes", Tag::ByteArray(vec![1, -1, 127])),
+            ("string", Tag::String("minecraft:stone".to_owned())),
+            ("list", Tag::List(vec![Tag::Int(1), Tag::Int(2)])),
+            ("empty_list", Tag::List(Vec::new())),
+            ("nested", compound(vec![("inner", Tag::Short(7))])),
+            ("ints", Tag::IntArray(vec![i32::MIN, 0, i32::MAX])),
+            ("longs", Tag::LongArray(vec![i64::MIN, 0, i64::MAX])),
+        ]);
+
+        let (name, read) = read_nbt(write_nbt("chunk", &root).as_slice()).unwrap();
+        assert_eq!(name, "chunk");
+        assert_eq!(read, root);
+        assert_eq!(read.get("nested").and_then(|n| n.get("inner")).and_then(Tag::as_i64), Some(7));
+    }
+
+    fn assert_invalid(bytes: &[u8], kind: io::ErrorKind) {
+        let err = read_nbt(bytes).err().unwrap_or_else(|| panic!("{bytes:?} was accepted"));
+        assert_eq!(err.kind(), kind, "{err}");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        // Empty, and a root that isn't a compound
+        assert_invalid(&[], io::ErrorKind::UnexpectedEof);
+        assert_invalid(&[3, 0, 0, 0, 0, 0, 1], io::ErrorKind::InvalidData);
+
+        // Unknown tag type 13 inside the root
+        assert_invalid(&[10, 0, 0, 13, 0, 1, b'x'], io::ErrorKind::InvalidData);
+
+        // Negative array length
+        let mut negative = vec![10, 0, 0, 12, 0, 1, b'x'];
+        negative.extend_from_slice(&(-1i32).to_be_bytes());
+        assert_invalid(&negative, io::ErrorKind::InvalidData);
+
+        // A byte array claiming far more bytes than there are
+        let mut truncated = vec![10, 0, 0, 7, 0, 1, b'x'];
+        truncated.extend_from_slice(&i32::MAX.to_be_bytes());
+        truncated.extend_from_slice(&[1, 2, 3]);
+        assert_invalid(&truncated, io::ErrorKind::UnexpectedEof);
+
+        // Compound without its end tag
+        let bytes = write_nbt("", &compound(vec![("a", Tag::Int(1))]));
+        assert_invalid(&bytes[..bytes.len() - 1], io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        // Lists of lists, one level deeper than allowed
+        let mut bytes = vec![10, 0, 0, 9, 0, 1, b'x'];
+        for _ in 0..MAX_DEPTH {
+            bytes.push(9);
+            bytes.extend_from_slice(&1i32.to_be_bytes());
+        }
+        bytes.push(TAG_END);
+        bytes.extend_from_slice(&0i32.to_be_bytes());
+
+        assert_invalid(&bytes, io::ErrorKind::InvalidData);
+    }
+}
@@ -157,7 +157,8 @@ impl Renderer {
         }.push(quad_props);
     }
 
-    pub fn end_batch(&mut self, program: &mut ShaderProgram) {
+    // Returns the number of draw calls
+    pub fn end_batch(&mut self, program: &mut ShaderProgram) -> u32 {
         let mut draw_calls = 0;
 
         // TODO: Handle quads without textures
@@ -210,8 +211,8 @@ impl Renderer {
 
             draw_calls += 1; // e.g. 100개인데 texture_unit이 15이면 7번 그려야 하니까 draw_calls는 7번임.
         }
-        
-        println!("Total draw calls: {draw_calls}");
+
+        draw_calls
     }
 
 }
@@ -7,13 +7,17 @@ uniform sampler2D tex;
 in VertexAttributes {
     vec3 frag_pos;
     vec2 texture_coords;
+    flat vec4 tile;
+    float brightness;
 } attrs;
 
 void main() {
-    vec4 diffuse_frag = texture(tex, attrs.texture_coords);
+    // Faces merged by the greedy mesher span several blocks: wrap around inside the tile
+    vec2 uv = mix(attrs.tile.xy, attrs.tile.zw, fract(attrs.texture_coords));
+    vec4 diffuse_frag = texture(tex, uv);
 
     if(diffuse_frag.a == 0) {
         discard;
     }
-    Color = diffuse_frag;
+    Color = vec4(diffuse_frag.rgb * attrs.brightness, diffuse_frag.a);
 }
\ No newline at end of file
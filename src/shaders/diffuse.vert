@@ -1,20 +1,61 @@
 #version 460 core
 
-uniform mat4 model;
 uniform mat4 view;
 uniform mat4 projection;
 
-layout (location = 0) in vec3 pos;
-layout (location = 1) in vec2 texture_coords;
+// Packed vertex, see shapes::VERTEX_WORDS
+layout (location = 0) in uvec2 vertex;
+
+// World position of each drawn chunk's origin, see chunk_buffer::ChunkBuffer
+layout (std430, binding = 0) readonly buffer ChunkOrigins {
+    ivec4 chunk_origins[];
+};
+
+// Same as mesher::ATLAS_TILES
+const float ATLAS_TILES = 64.0;
 
 out VertexAttributes {
     vec3 frag_pos;
     vec2 texture_coords;
+    flat vec4 tile;
+    float brightness;
 } attrs;
 
 void main() {
-    gl_Position = projection * view * model * vec4(pos, 1.0);
+    vec3 pos;
+    float ao; // 0: fully occluded corner, 3: open
+    uint tile;
+    vec2 light; // sky light, block light
+
+    if ((vertex.x >> 31) != 0u) {
+        // Block model vertex, in 16ths of a block and of the tile
+        pos = vec3(vertex.x & 511u, (vertex.x >> 9) & 511u, (vertex.x >> 18) & 511u) / 16.0 - 1.0;
+        ao = 3.0;
+        tile = (vertex.y >> 10) & 0xFFFu;
+        light = vec2((vertex.x >> 27) & 15u, (vertex.y >> 22) & 15u);
+        attrs.texture_coords = vec2(vertex.y & 31u, (vertex.y >> 5) & 31u) / 16.0;
+    } else {
+        pos = vec3(vertex.x & 31u, (vertex.x >> 5) & 31u, (vertex.x >> 10) & 31u);
+        uint corner = (vertex.x >> 18) & 3u;
+        ao = float((vertex.x >> 20) & 3u);
+        vec2 blocks = vec2(((vertex.x >> 22) & 15u) + 1u, ((vertex.x >> 26) & 15u) + 1u);
+
+        tile = vertex.y & 0xFFFFu;
+        light = vec2((vertex.y >> 16) & 0xFFu, vertex.y >> 24) / 16.0;
+        // In blocks: the tile repeats once per block
+        attrs.texture_coords = vec2(corner == 1u || corner == 2u, corner >= 2u) * blocks;
+    }
+    pos += vec3(chunk_origins[gl_DrawID].xyz);
+
+    gl_Position = projection * view * vec4(pos, 1.0);
+
+    attrs.frag_pos = vec3(view * vec4(pos, 1.0f)); // 색깔 칠할 때는 Vec3만 알아도 된다고 한다.
+
+    vec2 tile_start = vec2(tile % uint(ATLAS_TILES), tile / uint(ATLAS_TILES)) / ATLAS_TILES;
+    attrs.tile = vec4(tile_start, tile_start + 1.0 / ATLAS_TILES);
 
-    attrs.frag_pos = vec3(view * model * vec4(pos, 1.0f)); // 색깔 칠할 때는 Vec3만 알아도 된다고 한다.
-    attrs.texture_coords = texture_coords;
+    // Every level is 20% darker than the one above, with a little ambient light left in caves
+    float level = max(light.x, light.y);
+    float occlusion = 0.4 + 0.2 * ao;
+    attrs.brightness = max(pow(0.8, 15.0 - level), 0.05) * occlusion;
 }
\ No newline at end of file
@@ -1,104 +1,104 @@
-use crate::chunk_manager::Sides;
-use crate::UVFaces;
+use crate::block_model::ModelQuad;
 
+// Corners of each face, in the order of Sides, as offsets from the block origin.
+// Corner i uses the uv (u, v) picked by FACE_UVS[i].
 #[rustfmt::skip]
-pub unsafe fn write_unit_cube_to_ptr (
-    ptr: *mut f32,
-    position: (f32, f32, f32),
-    (front_uv, back_uv, top_uv, bottom_uv, left_uv, right_uv): UVFaces,
-    [right, left, top, bottom, front, back]: Sides,
-) -> u32 {
-    let (x, y, z) = position;
-
-    let (vertex_size, vertices_per_face) = (5, 6); // 5: x,y,z,uv_...,uv_...
-    let face_size = vertex_size * vertices_per_face;
-
-    let mut idx = 0;
-    let mut copied_vertices = 0;
-
-    if front {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            0.0 + x, 0.0 + y, 1.0 + z, front_uv.0, front_uv.1,
-            1.0 + x, 0.0 + y, 1.0 + z, front_uv.2, front_uv.1,
-            1.0 + x, 1.0 + y, 1.0 + z, front_uv.2, front_uv.3,
-            1.0 + x, 1.0 + y, 1.0 + z, front_uv.2, front_uv.3,
-            0.0 + x, 1.0 + y, 1.0 + z, front_uv.0, front_uv.3,
-            0.0 + x, 0.0 + y, 1.0 + z, front_uv.0, front_uv.1,
-        ].as_ptr(), face_size);
-
-        idx += face_size as isize;
-        copied_vertices += vertices_per_face;
-    }
+pub const FACE_CORNERS: [[(u32, u32, u32); 4]; 6] = [
+    [(1, 0, 1), (1, 0, 0), (1, 1, 0), (1, 1, 1)], // right
+    [(0, 0, 0), (0, 0, 1), (0, 1, 1), (0, 1, 0)], // left
+    [(0, 1, 1), (1, 1, 1), (1, 1, 0), (0, 1, 0)], // top
+    [(0, 0, 0), (1, 0, 0), (1, 0, 1), (0, 0, 1)], // bottom
+    [(0, 0, 1), (1, 0, 1), (1, 1, 1), (0, 1, 1)], // front
+    [(1, 0, 0), (0, 0, 0), (0, 1, 0), (1, 1, 0)], // back
+];
 
-    if back {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            1.0 + x, 0.0 + y, 0.0 + z, back_uv.0, back_uv.1,
-            0.0 + x, 0.0 + y, 0.0 + z, back_uv.2, back_uv.1,
-            0.0 + x, 1.0 + y, 0.0 + z, back_uv.2, back_uv.3,
-            0.0 + x, 1.0 + y, 0.0 + z, back_uv.2, back_uv.3,
-            1.0 + x, 1.0 + y, 0.0 + z, back_uv.0, back_uv.3,
-            1.0 + x, 0.0 + y, 0.0 + z, back_uv.0, back_uv.1,
-        ].as_ptr(), face_size);
-
-        idx += face_size as isize;
-        copied_vertices += vertices_per_face;
-    }
+// Per corner: sky light, block light, ambient occlusion (0: fully occluded, 3: open)
+pub type CornerShading = [f32; 3];
 
-    if left {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            0.0 + x, 0.0 + y, 0.0 + z, left_uv.0, left_uv.1,
-            0.0 + x, 0.0 + y, 1.0 + z, left_uv.2, left_uv.1,
-            0.0 + x, 1.0 + y, 1.0 + z, left_uv.2, left_uv.3,
-            0.0 + x, 1.0 + y, 1.0 + z, left_uv.2, left_uv.3,
-            0.0 + x, 1.0 + y, 0.0 + z, left_uv.0, left_uv.3,
-            0.0 + x, 0.0 + y, 0.0 + z, left_uv.0, left_uv.1,
-            ].as_ptr(), face_size);
-    
-        idx += face_size as isize;
-        copied_vertices += vertices_per_face;
-    }
+// Packed vertex, two u32:
+//   0: x (5 bits) | y (5) | z (5) | side (3) | corner (2) | ao (2) | u blocks - 1 (4) | v blocks - 1 (4) | 0 (2)
+//   1: atlas tile (16 bits) | sky light * 16 (8) | block light * 16 (8)
+// Positions are chunk-local and go up to 16 included. The corner picks the tile's uv the same way
+// for every face (0: start, start / 1: end, start / 2: end, end / 3: start, end) and the shader
+// repeats the tile once per block over the u and v blocks of the quad.
+//
+// Block model vertices (see write_model_face_to_ptr) have the highest bit set instead:
+//   0: x (9 bits) | y (9) | z (9) | sky light (4) | 1
+//   1: u (5) | v (5) | atlas tile (12) | block light (4)
+// Positions are in 16ths of a block from one block before the chunk, since rotated boxes can
+// stick out of it a little. uvs are in 16ths of the tile.
+pub const VERTEX_WORDS: usize = 2;
 
-    if right {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            1.0 + x, 0.0 + y, 1.0 + z, right_uv.0, right_uv.1,
-            1.0 + x, 0.0 + y, 0.0 + z, right_uv.2, right_uv.1,
-            1.0 + x, 1.0 + y, 0.0 + z, right_uv.2, right_uv.3,
-            1.0 + x, 1.0 + y, 0.0 + z, right_uv.2, right_uv.3,
-            1.0 + x, 1.0 + y, 1.0 + z, right_uv.0, right_uv.3,
-            1.0 + x, 0.0 + y, 1.0 + z, right_uv.0, right_uv.1,
-        ].as_ptr(), face_size);
-
-        idx += face_size as isize;
-        copied_vertices += vertices_per_face;
+pub const MODEL_VERTEX: u32 = 1 << 31;
+
+// Chunk-local position of a packed vertex, in blocks
+pub fn vertex_position(vertex: &[u32]) -> (f32, f32, f32) {
+    if vertex[0] & MODEL_VERTEX != 0 {
+        let coordinate = |shift: u32| ((vertex[0] >> shift) & 511) as f32 / 16.0 - 1.0;
+        (coordinate(0), coordinate(9), coordinate(18))
+    } else {
+        ((vertex[0] & 31) as f32, ((vertex[0] >> 5) & 31) as f32, ((vertex[0] >> 10) & 31) as f32)
     }
+}
+
+// Two triangles out of the four vertices of a quad. Shared by every quad of every chunk mesh.
+pub const QUAD_INDICES: [u32; 6] = [0, 1, 2, 2, 3, 0];
+
+// Writes the four vertices of a face covering `size` blocks. Along the normal the size is the
+// thickness of the block or LOD cell the face belongs to.
+// QUAD_INDICES splits the quad along the diagonal from its first vertex. When corners 0 and 2 add up
+// to more ambient occlusion light than corners 1 and 3, the corners start at 1 so the split runs
+// between the darker pair; otherwise the interpolation makes the shading lopsided.
+pub unsafe fn write_face_to_ptr(
+    ptr: *mut u32,
+    (x, y, z): (u32, u32, u32),
+    (width, height, depth): (u32, u32, u32),
+    side: usize,
+    tile: u32,
+    shading: [CornerShading; 4],
+) -> u32 {
+    let corners = FACE_CORNERS[side].map(|(cx, cy, cz)| (x + cx * width, y + cy * height, z + cz * depth));
+    let length = |(ax, ay, az): (u32, u32, u32), (bx, by, bz): (u32, u32, u32)| ax.abs_diff(bx) + ay.abs_diff(by) + az.abs_diff(bz);
+    // Blocks covered along the u and v directions of the texture
+    let (u_blocks, v_blocks) = (length(corners[0], corners[1]), length(corners[1], corners[2]));
 
-    if top {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            0.0 + x, 1.0 + y, 1.0 + z, top_uv.0, top_uv.1,
-            1.0 + x, 1.0 + y, 1.0 + z, top_uv.2, top_uv.1,
-            1.0 + x, 1.0 + y, 0.0 + z, top_uv.2, top_uv.3,
-            1.0 + x, 1.0 + y, 0.0 + z, top_uv.2, top_uv.3,
-            0.0 + x, 1.0 + y, 0.0 + z, top_uv.0, top_uv.3,
-            0.0 + x, 1.0 + y, 1.0 + z, top_uv.0, top_uv.1,
-        ].as_ptr(), face_size);
-
-        idx += face_size as isize;
-        copied_vertices += vertices_per_face;
+    let flipped = shading[0][2] + shading[2][2] > shading[1][2] + shading[3][2];
+    let order = if flipped { [1, 2, 3, 0] } else { [0, 1, 2, 3] };
+
+    for (i, &corner) in order.iter().enumerate() {
+        let (cx, cy, cz) = corners[corner];
+        let [sky, block, ao] = shading[corner];
+
+        let position = cx | cy << 5 | cz << 10;
+        let face = (side as u32) << 15 | (corner as u32) << 18 | (ao as u32) << 20;
+        let repeat = (u_blocks - 1) << 22 | (v_blocks - 1) << 26;
+        let light = ((sky * 16.0).round() as u32) << 16 | ((block * 16.0).round() as u32) << 24;
+
+        ptr.add(i * VERTEX_WORDS).copy_from_nonoverlapping([position | face | repeat, tile | light].as_ptr(), VERTEX_WORDS);
     }
 
-    if bottom {
-        ptr.offset(idx).copy_from_nonoverlapping([
-            0.0 + x, 0.0 + y, 0.0 + z, bottom_uv.0, bottom_uv.1,
-            1.0 + x, 0.0 + y, 0.0 + z, bottom_uv.2, bottom_uv.1,
-            1.0 + x, 0.0 + y, 1.0 + z, bottom_uv.2, bottom_uv.3,
-            1.0 + x, 0.0 + y, 1.0 + z, bottom_uv.2, bottom_uv.3,
-            0.0 + x, 0.0 + y, 1.0 + z, bottom_uv.0, bottom_uv.3,
-            0.0 + x, 0.0 + y, 0.0 + z, bottom_uv.0, bottom_uv.1,
-        ].as_ptr(), face_size);
-
-        // idx += face_size as isize;
-        copied_vertices += vertices_per_face;
+    order.len() as u32
+}
+
+// Writes the four vertices of a block model face for the block at `(x, y, z)`, lit by `light`
+// (sky, block) without ambient occlusion
+pub unsafe fn write_model_face_to_ptr(
+    ptr: *mut u32,
+    (x, y, z): (u32, u32, u32),
+    quad: &ModelQuad,
+    tile: u32,
+    (sky, block): (u8, u8),
+) -> u32 {
+    for (i, (corner, uv)) in quad.corners.iter().zip(quad.uvs.iter()).enumerate() {
+        let coordinate = |block: u32, offset: f32| (((block as f32 + offset + 1.0) * 16.0).round() as u32).min(511);
+        let position = coordinate(x, corner[0]) | coordinate(y, corner[1]) << 9 | coordinate(z, corner[2]) << 18;
+        let texture = |value: f32| ((value * 16.0).round() as u32).min(16);
+
+        let first = position | (sky as u32) << 27 | MODEL_VERTEX;
+        let second = texture(uv[0]) | texture(uv[1]) << 5 | tile << 10 | (block as u32) << 22;
+
+        ptr.add(i * VERTEX_WORDS).copy_from_nonoverlapping([first, second].as_ptr(), VERTEX_WORDS);
     }
 
-    copied_vertices as u32
+    quad.corners.len() as u32
 }
\ No newline at end of file
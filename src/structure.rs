@@ -0,0 +1,108 @@
+use crate::chunk::BlockID;
+use std::collections::HashMap;
+
+// Structure blocks waiting for a chunk that isn't loaded yet, keyed by chunk coordinates.
+// Applied when that chunk is generated or loaded.
+pub type PendingBlocks = HashMap<(i32, i32, i32), Vec<((u32, u32, u32), BlockID)>>;
+
+// A prefab placed by the world generator (tree, rock, ruin...). Offsets are relative to the
+// origin, which is the block right above the ground the structure stands on.
+// Structure blocks only ever replace air.
+#[derive(Clone, Debug)]
+pub struct StructureTemplate {
+    pub blocks: Vec<((i32, i32, i32), BlockID)>,
+}
+
+impl StructureTemplate {
+    // Blocks turned by `quarter_turns` * 90 degrees around the origin
+    pub fn rotated(&self, quarter_turns: u8) -> impl Iterator<Item = ((i32, i32, i32), BlockID)> + '_ {
+        self.blocks.iter().map(move |&((x, y, z), block)| {
+            let (x, z) = match quarter_turns % 4 {
+                0 => (x, z),
+                1 => (-z, x),
+                2 => (-x, -z),
+                _ => (z, -x),
+            };
+
+            ((x, y, z), block)
+        })
+    }
+
+    pub fn oak_tree(log: BlockID, leaves: BlockID, height: i32) -> StructureTemplate {
+        let mut blocks = Vec::new();
+
+        for i in 0..height {
+            blocks.push(((0, i, 0), log));
+        }
+
+        for y in height - 3..=height - 2 {
+            for x in -2..=2 {
+                for z in -2..=2 {
+                    if x != 0 || z != 0 {
+                        blocks.push(((x, y, z), leaves));
+                    }
+                }
+            }
+        }
+
+        for x in -1..=1 {
+            for z in -1..=1 {
+                if x != 0 || z != 0 {
+                    blocks.push(((x, height - 1, z), leaves));
+                }
+            }
+        }
+
+        for (x, z) in [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)] {
+            blocks.push(((x, height, z), leaves));
+        }
+
+        StructureTemplate { blocks }
+    }
+
+    // Small boulder, stone with cobblestone patches
+    pub fn rock(stone: BlockID, cobblestone: BlockID) -> StructureTemplate {
+        let mut blocks = Vec::new();
+
+        for y in 0..=1 {
+            for x in -1..=1i32 {
+                for z in -1..=1i32 {
+                    // Rounded: no corners on the top layer
+                    if y == 1 && x.abs() + z.abs() > 1 {
+                        continue;
+                    }
+
+                    let block = if (x + z + y).rem_euclid(3) == 0 { cobblestone } else { stone };
+                    blocks.push(((x, y, z), block));
+                }
+            }
+        }
+
+        StructureTemplate { blocks }
+    }
+
+    // Crumbling 7x7 cobblestone walls with a doorway
+    pub fn ruin(cobblestone: BlockID) -> StructureTemplate {
+        let mut blocks = Vec::new();
+
+        for x in -3..=3i32 {
+            for z in -3..=3i32 {
+                let on_wall = x.abs() == 3 || z.abs() == 3;
+                let doorway = z == -3 && x.abs() <= 1;
+
+                if !on_wall || doorway {
+                    continue;
+                }
+
+                // Fixed pattern of broken wall heights
+                let height = 1 + (x * 7 + z * 3).rem_euclid(4);
+
+                for y in 0..height {
+                    blocks.push(((x, y, z), cobblestone));
+                }
+            }
+        }
+
+        StructureTemplate { blocks }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::chunk_manager::CHUNK_SIZE;
+use crate::mesher::NEIGHBOUR_OFFSETS;
+use std::collections::VecDeque;
+
+// Which faces of a chunk can see each other through its non-opaque blocks. Sides are numbered
+// in the order of NEIGHBOUR_OFFSETS.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct VisibilityGraph {
+    // Bit j of connections[i]: side i and side j are connected
+    connections: [u8; 6],
+}
+
+impl VisibilityGraph {
+    // Every face sees every other one, e.g. an empty chunk or one that isn't meshed yet
+    pub const OPEN: VisibilityGraph = VisibilityGraph { connections: [0b111111; 6] };
+
+    // Flood fills the open blocks of the chunk and connects the faces each filled area touches
+    pub fn compute(is_open: impl Fn(i32, i32, i32) -> bool) -> VisibilityGraph {
+        let size = CHUNK_SIZE as i32;
+        let index = |x: i32, y: i32, z: i32| (y * size * size + z * size + x) as usize;
+
+        let mut graph = VisibilityGraph { connections: [0; 6] };
+        let mut visited = vec![false; (size * size * size) as usize];
+        let mut queue = VecDeque::new();
+
+        for y in 0..size {
+            for z in 0..size {
+                for x in 0..size {
+                    if visited[index(x, y, z)] || !is_open(x, y, z) {
+                        continue;
+                    }
+
+                    // Faces touched by this area
+                    let mut touched = 0u8;
+                    visited[index(x, y, z)] = true;
+                    queue.push_back((x, y, z));
+
+                    while let Some((x, y, z)) = queue.pop_front() {
+                        for (side, (dx, dy, dz)) in NEIGHBOUR_OFFSETS.iter().enumerate() {
+                            let (nx, ny, nz) = (x + dx, y + dy, z + dz);
+
+                            if ![nx, ny, nz].iter().all(|v| (0..size).contains(v)) {
+                                touched |= 1 << side;
+                                continue;
+                            }
+
+                            if !visited[index(nx, ny, nz)] && is_open(nx, ny, nz) {
+                                visited[index(nx, ny, nz)] = true;
+                                queue.push_back((nx, ny, nz));
+                            }
+                        }
+                    }
+
+                    for side in 0..6 {
+                        if touched & (1 << side) != 0 {
+                            graph.connections[side] |= touched;
+                        }
+                    }
+                }
+            }
+        }
+
+        graph
+    }
+
+    pub fn connects(&self, from: usize, to: usize) -> bool {
+        self.connections[from] & (1 << to) != 0
+    }
+}
+
+// Side on the other end of a neighbour offset: right <-> left, top <-> bottom, front <-> back
+pub fn opposite(side: usize) -> usize {
+    side ^ 1
+}
@@ -0,0 +1,550 @@
+use crate::biome::{Biome, Climate};
+use crate::block_registry::BlockRegistry;
+use crate::chunk::{BlockID, ChunkData};
+use crate::chunk_manager::CHUNK_SIZE;
+use crate::structure::StructureTemplate;
+use noise::{NoiseFn, SuperSimplex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+
+pub struct GeneratedChunk {
+    pub chunk: ChunkData,
+    // Blocks of structures starting in this chunk that reach into other chunks, in world coordinates
+    pub outside_blocks: Vec<((i32, i32, i32), BlockID)>,
+}
+
+// Builds the world one chunk at a time. A chunk must only depend on the seed and its
+// coordinates, so chunks can be generated in any order (or on any thread) and always
+// come out the same.
+pub trait WorldGenerator: Send + Sync {
+    fn seed(&self) -> u32;
+
+    fn generate_chunk(&self, coords: (i32, i32, i32)) -> GeneratedChunk;
+
+    fn biome_at(&self, x: i32, z: i32) -> Biome;
+}
+
+// RNG dedicated to one chunk column, independent of generation order
+pub fn column_rng(seed: u32, chunk_x: i32, chunk_z: i32) -> StdRng {
+    // splitmix64 finalizer over the packed inputs
+    let mut h = (seed as u64) << 32 ^ (chunk_x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (chunk_z as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+
+    StdRng::seed_from_u64(h)
+}
+
+// RNG dedicated to one chunk
+pub fn chunk_rng(seed: u32, (chunk_x, chunk_y, chunk_z): (i32, i32, i32)) -> StdRng {
+    // Offset so it never matches the column RNG of the same seed
+    let layer = (chunk_y as u32).wrapping_add(1).wrapping_mul(0x9E37_79B9) | 1;
+
+    column_rng(seed ^ layer, chunk_x, chunk_z)
+}
+
+// Resolves a block the generator needs from the registry
+pub fn required_block(registry: &BlockRegistry, name: &str) -> BlockID {
+    registry.by_name(name).unwrap_or_else(|| panic!("Block '{name}' is not registered"))
+}
+
+// One [[ore]] entry of ores.toml. Ranges are inclusive.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OreVein {
+    pub block: String,
+    pub height: (i32, i32),
+    pub vein_size: (u32, u32),
+    pub veins_per_chunk: (u32, u32),
+}
+
+#[derive(Deserialize)]
+struct OreFile {
+    ore: Vec<OreVein>,
+}
+
+// Checked here, since a bad entry would otherwise only panic later in a worker
+pub fn ores_from_toml(source: &str, registry: &BlockRegistry) -> Result<Vec<OreVein>, String> {
+    let file: OreFile = toml::from_str(source).map_err(|err| err.to_string())?;
+
+    for ore in &file.ore {
+        if registry.by_name(&ore.block).is_none() {
+            return Err(format!("Ore block '{}' is not registered", ore.block));
+        }
+        if ore.height.0 > ore.height.1 {
+            return Err(format!("Ore '{}' has a height range with min > max", ore.block));
+        }
+        if ore.vein_size.0 > ore.vein_size.1 {
+            return Err(format!("Ore '{}' has a vein_size with min > max", ore.block));
+        }
+        if ore.veins_per_chunk.0 > ore.veins_per_chunk.1 {
+            return Err(format!("Ore '{}' has a veins_per_chunk with min > max", ore.block));
+        }
+    }
+
+    Ok(file.ore)
+}
+
+pub fn load_ores(path: &str, registry: &BlockRegistry) -> Result<Vec<OreVein>, String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("Filename: {path}, error: {err}"))?;
+    ores_from_toml(&source, registry).map_err(|err| format!("Filename: {path}, error: {err}"))
+}
+
+const MAX_TERRAIN_HEIGHT: i32 = 64;
+const TREE_HEIGHT: i32 = 5;
+// Chance for each column of chunks to have a ruin, in the biomes that allow them
+const RUIN_CHANCE: f32 = 0.05;
+// Mixed into the seed of the plant RNG, so plants don't shift the structure rolls
+const PLANT_SEED: u32 = 0x5EED_F10A;
+
+// How far 3D noise may push the ground above or below the heightmap, for overhangs and arches
+const OVERHANG_BASE: f64 = 4.0;
+const OVERHANG_PER_AMPLITUDE: f64 = 0.5;
+const MAX_OVERHANG: i32 = 16;
+
+// Blocks of surface and filler on top of the stone
+const SOIL_DEPTH: i32 = 3;
+// Big caverns stay this far below the surface, only tunnels break through it
+const CAVERN_MIN_DEPTH: f64 = 8.0;
+const CAVERN_THRESHOLD: f64 = 0.6;
+const TUNNEL_RADIUS: f64 = 0.06;
+
+// Terrain of one column, before the 3D noise
+struct Column {
+    height: f64,
+    overhang: f64,
+    biome: Biome,
+}
+
+// Density-based terrain: a biome-shaped heightmap turned into a density gradient and
+// distorted by 3D noise, so ground can hang over air. Caverns and tunnels are carved out of it.
+// Surface and filler blocks over stone with ore veins, then trees, rocks, ruins and plants on top.
+// Nothing below y = 0.
+pub struct SimplexGenerator {
+    seed: u32,
+    noise: SuperSimplex,
+    density_noise: SuperSimplex,
+    cavern_noise: SuperSimplex,
+    // A tunnel runs where both fields are close to zero
+    tunnel_noise: (SuperSimplex, SuperSimplex),
+    climate: Climate,
+    // (surface, filler) of each biome, indexed by `Biome as usize`
+    biome_blocks: [(BlockID, BlockID); Biome::ALL.len()],
+    // Plants growing on the surface of each biome, indexed the same way
+    plants: [Vec<BlockID>; Biome::ALL.len()],
+    ores: Vec<(BlockID, OreVein)>,
+    stone: BlockID,
+    oak_tree: StructureTemplate,
+    rock: StructureTemplate,
+    ruin: StructureTemplate,
+}
+
+impl SimplexGenerator {
+    pub fn new(seed: u32, registry: &BlockRegistry, ores: &[OreVein]) -> SimplexGenerator {
+        let biome_blocks = Biome::ALL.map(|biome| {
+            let (surface, filler) = biome.surface_blocks();
+            (required_block(registry, surface), required_block(registry, filler))
+        });
+        let plants = Biome::ALL.map(|biome| biome.plants().iter().map(|name| required_block(registry, name)).collect());
+        let stone = required_block(registry, "stone");
+        let cobblestone = required_block(registry, "cobblestone");
+        let oak_log = required_block(registry, "oak_log");
+        let oak_leaves = required_block(registry, "oak_leaves");
+
+        SimplexGenerator {
+            seed,
+            noise: SuperSimplex::new(seed),
+            density_noise: SuperSimplex::new(seed.wrapping_add(3)),
+            cavern_noise: SuperSimplex::new(seed.wrapping_add(4)),
+            tunnel_noise: (SuperSimplex::new(seed.wrapping_add(5)), SuperSimplex::new(seed.wrapping_add(6))),
+            climate: Climate::new(seed),
+            biome_blocks,
+            plants,
+            ores: ores.iter().map(|ore| (required_block(registry, &ore.block), ore.clone())).collect(),
+            stone,
+            oak_tree: StructureTemplate::oak_tree(oak_log, oak_leaves, TREE_HEIGHT),
+            rock: StructureTemplate::rock(stone, cobblestone),
+            ruin: StructureTemplate::ruin(cobblestone),
+        }
+    }
+
+    fn column(&self, x: i32, z: i32) -> Column {
+        let (xf, zf) = (x as f64 / 64.0, z as f64 / 64.0);
+        let shape = self.climate.blended_shape(x, z);
+
+        Column {
+            height: shape.base + shape.amplitude * self.noise.get([xf, zf]),
+            overhang: OVERHANG_BASE + OVERHANG_PER_AMPLITUDE * shape.amplitude,
+            biome: self.climate.biome_at(x, z),
+        }
+    }
+
+    fn is_cave(&self, column: &Column, x: i32, y: i32, z: i32) -> bool {
+        let (xf, yf, zf) = (x as f64, y as f64, z as f64);
+
+        // Cheese caves: large blobs
+        if yf < column.height - CAVERN_MIN_DEPTH
+            && self.cavern_noise.get([xf / 48.0, yf / 24.0, zf / 48.0]) > CAVERN_THRESHOLD
+        {
+            return true;
+        }
+
+        // Worm caves: the intersection of two noise isosurfaces is a winding tube
+        let p = [xf / 40.0, yf / 30.0, zf / 40.0];
+        self.tunnel_noise.0.get(p).abs() < TUNNEL_RADIUS && self.tunnel_noise.1.get(p).abs() < TUNNEL_RADIUS
+    }
+
+    fn is_solid(&self, column: &Column, x: i32, y: i32, z: i32) -> bool {
+        if y < 0 {
+            return false;
+        }
+
+        // The floor of the world is never carved
+        if y == 0 {
+            return true;
+        }
+
+        let distortion = self.density_noise.get([x as f64 / 32.0, y as f64 / 24.0, z as f64 / 32.0]);
+        let density = column.height - y as f64 + distortion * column.overhang;
+
+        density > 0.0 && !self.is_cave(column, x, y, z)
+    }
+
+    // Highest solid block of a column
+    fn ground_height(&self, column: &Column, x: i32, z: i32) -> i32 {
+        let top = (column.height + column.overhang).ceil() as i32;
+
+        (0..=top).rev().find(|&y| self.is_solid(column, x, y, z)).unwrap_or(0)
+    }
+
+    // Structures of a chunk column: world (x, z) of the origin, template and rotation
+    fn structures_in_column(&self, chunk_x: i32, chunk_z: i32) -> Vec<((i32, i32), &StructureTemplate, u8)> {
+        let mut rng = column_rng(self.seed, chunk_x, chunk_z);
+        let mut structures = Vec::new();
+        let size = CHUNK_SIZE as i32;
+
+        for z in 0..size {
+            for x in 0..size {
+                let (x, z) = (chunk_x * size + x, chunk_z * size + z);
+                let biome = self.climate.biome_at(x, z);
+
+                // Rolled for every column so the sequence doesn't depend on the biomes
+                let (tree_roll, rock_roll): (f32, f32) = (rng.gen(), rng.gen());
+
+                if tree_roll < biome.tree_density() {
+                    structures.push(((x, z), &self.oak_tree, 0));
+                } else if rock_roll < biome.rock_density() {
+                    structures.push(((x, z), &self.rock, 0));
+                }
+            }
+        }
+
+        let (ruin_roll, x, z, rotation): (f32, _, _, _) =
+            (rng.gen(), chunk_x * size + rng.gen_range(0..size), chunk_z * size + rng.gen_range(0..size), rng.gen_range(0..4));
+
+        if ruin_roll < RUIN_CHANCE && self.climate.biome_at(x, z).has_ruins() {
+            structures.push(((x, z), &self.ruin, rotation));
+        }
+
+        structures
+    }
+
+    // Plants on the surface of the columns of this chunk, above structures so they only fill air
+    fn place_plants(&self, chunk: &mut ChunkData, (cx, cy, cz): (i32, i32, i32)) {
+        let mut rng = column_rng(self.seed ^ PLANT_SEED, cx, cz);
+        let size = CHUNK_SIZE as i32;
+        let (min_y, max_y) = (cy * size, cy * size + size - 1);
+
+        for lz in 0..size {
+            for lx in 0..size {
+                let (x, z) = (cx * size + lx, cz * size + lz);
+                let biome = self.climate.biome_at(x, z);
+
+                // Rolled for every column so the sequence doesn't depend on the biomes
+                let (plant_roll, pick): (f32, usize) = (rng.gen(), rng.gen());
+                let plants = &self.plants[biome as usize];
+
+                if plants.is_empty() || plant_roll >= biome.plant_density() {
+                    continue;
+                }
+
+                // The ground is somewhere between these, don't look for it if the plant can't be in this chunk
+                let column = self.column(x, z);
+                let (low, high) = (column.height - column.overhang, column.height + column.overhang);
+                if (max_y as f64) < low || min_y as f64 > high.ceil() + 1.0 {
+                    continue;
+                }
+
+                let ground = self.ground_height(&column, x, z);
+                let y = ground + 1;
+
+                // Same as structures: only on the surface block, not on a cave floor
+                if !(min_y..=max_y).contains(&y) || (ground as f64) < low {
+                    continue;
+                }
+
+                let (lx, ly, lz) = (lx as u32, (y - min_y) as u32, lz as u32);
+                if chunk.get_block(lx, ly, lz).is_air() {
+                    chunk.set_block(lx, ly, lz, plants[pick % plants.len()]);
+                }
+            }
+        }
+    }
+
+    // Grows the ore veins starting in this chunk. Veins only replace stone and are cut at the
+    // chunk border, so neighbouring chunks don't matter.
+    fn place_ores(&self, chunk: &mut ChunkData, coords: (i32, i32, i32)) {
+        let mut rng = chunk_rng(self.seed, coords);
+        let size = CHUNK_SIZE as i32;
+        let min_y = coords.1 * size;
+
+        for (block, ore) in &self.ores {
+            // Part of the chunk within the height range, in local coordinates
+            let (low, high) = (i32::max(ore.height.0 - min_y, 0), i32::min(ore.height.1 - min_y, size - 1));
+
+            if low > high {
+                continue;
+            }
+
+            for _ in 0..rng.gen_range(ore.veins_per_chunk.0..=ore.veins_per_chunk.1) {
+                let (mut x, mut y, mut z) = (rng.gen_range(0..size), rng.gen_range(low..=high), rng.gen_range(0..size));
+
+                // Random walk from the origin
+                for _ in 0..rng.gen_range(ore.vein_size.0..=ore.vein_size.1) {
+                    if chunk.get_block(x as u32, y as u32, z as u32) == self.stone {
+                        chunk.set_block(x as u32, y as u32, z as u32, *block);
+                    }
+
+                    match rng.gen_range(0..6) {
+                        0 => x = (x + 1).min(size - 1),
+                        1 => x = (x - 1).max(0),
+                        2 => y = (y + 1).min(size - 1),
+                        3 => y = (y - 1).max(0),
+                        4 => z = (z + 1).min(size - 1),
+                        _ => z = (z - 1).max(0),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl WorldGenerator for SimplexGenerator {
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    fn generate_chunk(&self, (cx, cy, cz): (i32, i32, i32)) -> GeneratedChunk {
+        let mut chunk = ChunkData::empty();
+        let mut outside_blocks = Vec::new();
+        let size = CHUNK_SIZE as i32;
+        let (min_y, max_y) = (cy * size, cy * size + size - 1);
+
+        // Nothing but air above the highest structure origin and below the floor of the world
+        if min_y > MAX_TERRAIN_HEIGHT + MAX_OVERHANG + 1 || max_y < 0 {
+            return GeneratedChunk { chunk, outside_blocks };
+        }
+
+        let local = |x: i32, y: i32, z: i32| {
+            let (lx, ly, lz) = (x - cx * size, y - min_y, z - cz * size);
+
+            if (0..size).contains(&lx) && (0..size).contains(&ly) && (0..size).contains(&lz) {
+                Some((lx as u32, ly as u32, lz as u32))
+            } else {
+                None
+            }
+        };
+
+        for z in cz * size..(cz + 1) * size {
+            for x in cx * size..(cx + 1) * size {
+                let column = self.column(x, z);
+                let (surface, filler) = self.biome_blocks[column.biome as usize];
+
+                // Skip the part of the column that is air for sure
+                let top = i32::min(max_y, (column.height + column.overhang).ceil() as i32);
+
+                // Solid blocks right above, counted from the blocks over this chunk
+                let mut depth = (top + 1..=top + SOIL_DEPTH)
+                    .rev()
+                    .fold(0, |depth, y| if self.is_solid(&column, x, y, z) { depth + 1 } else { 0 });
+
+                for y in (min_y..=top).rev() {
+                    if !self.is_solid(&column, x, y, z) {
+                        depth = 0;
+                        continue;
+                    }
+
+                    // Soil only covers the ground near the surface, not cave floors
+                    let near_surface = y as f64 > column.height - column.overhang - SOIL_DEPTH as f64;
+
+                    let block = match depth {
+                        0 if near_surface => surface,
+                        d if d < SOIL_DEPTH && near_surface => filler,
+                        _ => self.stone,
+                    };
+
+                    let (lx, ly, lz) = local(x, y, z).unwrap();
+                    chunk.set_block(lx, ly, lz, block);
+                    depth += 1;
+                }
+            }
+        }
+
+        self.place_ores(&mut chunk, (cx, cy, cz));
+
+        // A structure belongs to the chunk its origin is in. Blocks reaching out of it are
+        // handed back for the chunk manager to place.
+        for ((x, z), template, rotation) in self.structures_in_column(cx, cz) {
+            let column = self.column(x, z);
+            let ground = self.ground_height(&column, x, z);
+            let y = ground + 1;
+
+            // Not this chunk's, or the ground was carved away by a cave
+            if !(min_y..=max_y).contains(&y) || (ground as f64) < column.height - column.overhang {
+                continue;
+            }
+
+            for ((dx, dy, dz), block) in template.rotated(rotation) {
+                let (bx, by, bz) = (x + dx, y + dy, z + dz);
+
+                match local(bx, by, bz) {
+                    Some((lx, ly, lz)) => {
+                        if chunk.get_block(lx, ly, lz).is_air() {
+                            chunk.set_block(lx, ly, lz, block);
+                        }
+                    }
+                    None => outside_blocks.push(((bx, by, bz), block)),
+                }
+            }
+        }
+
+        self.place_plants(&mut chunk, (cx, cy, cz));
+
+        GeneratedChunk { chunk, outside_blocks }
+    }
+
+    fn biome_at(&self, x: i32, z: i32) -> Biome {
+        self.climate.biome_at(x, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_manager::CHUNK_VOLUME;
+    use std::collections::HashMap;
+
+    fn generator(seed: u32) -> SimplexGenerator {
+        let registry = BlockRegistry::load("blocks.toml").unwrap();
+        let ores = load_ores("ores.toml", &registry).unwrap();
+
+        SimplexGenerator::new(seed, &registry, &ores)
+    }
+
+    fn chunk_bytes(generated: &GeneratedChunk) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        generated.chunk.write_to(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn same_seed_gives_same_chunks_in_any_order() {
+        let coords: Vec<(i32, i32, i32)> = (-2..2)
+            .flat_map(|x| (0..4).flat_map(move |y| (-2..2).map(move |z| (x, y, z))))
+            .collect();
+
+        let forward = generator(1234);
+        let first: HashMap<_, _> = coords.iter().map(|&c| (c, forward.generate_chunk(c))).collect();
+
+        // A new generator, and every chunk generated again in another order
+        let backward = generator(1234);
+        for &c in coords.iter().rev() {
+            let generated = backward.generate_chunk(c);
+
+            assert_eq!(chunk_bytes(&generated), chunk_bytes(&first[&c]), "chunk {c:?} differs");
+            assert_eq!(generated.outside_blocks, first[&c].outside_blocks, "outside blocks of {c:?} differ");
+        }
+    }
+
+    // Groups of face-connected blocks of one kind in a chunk
+    fn count_clusters(chunk: &ChunkData, block: BlockID) -> u32 {
+        let size = CHUNK_SIZE as i32;
+        let mut seen = vec![false; (size * size * size) as usize];
+        let index = |(x, y, z): (i32, i32, i32)| ((y * size + z) * size + x) as usize;
+        let mut clusters = 0;
+
+        for start in (0..size).flat_map(|y| (0..size).flat_map(move |z| (0..size).map(move |x| (x, y, z)))) {
+            if seen[index(start)] || chunk.get_block(start.0 as u32, start.1 as u32, start.2 as u32) != block {
+                continue;
+            }
+
+            clusters += 1;
+            seen[index(start)] = true;
+            let mut stack = vec![start];
+
+            while let Some((x, y, z)) = stack.pop() {
+                for (dx, dy, dz) in [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)] {
+                    let next = (x + dx, y + dy, z + dz);
+                    let inside = [next.0, next.1, next.2].iter().all(|v| (0..size).contains(v));
+
+                    if inside && !seen[index(next)] && chunk.get_block(next.0 as u32, next.1 as u32, next.2 as u32) == block {
+                        seen[index(next)] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+        }
+
+        clusters
+    }
+
+    #[test]
+    fn ore_veins_per_chunk_stay_in_range() {
+        let registry = BlockRegistry::load("blocks.toml").unwrap();
+        let ores = load_ores("ores.toml", &registry).unwrap();
+        let size = CHUNK_SIZE as i32;
+
+        for ore in &ores {
+            // One ore at a time, so a vein is never split by another ore's blocks. A vein is a
+            // walk through a chunk of stone, so it is one cluster, or fewer when veins overlap.
+            let generator = SimplexGenerator::new(99, &registry, std::slice::from_ref(ore));
+            let block = required_block(&registry, &ore.block);
+            let (min_veins, max_veins) = ore.veins_per_chunk;
+
+            for coords in (-3..3).flat_map(|x| (0..6).flat_map(move |y| (-3..3).map(move |z| (x, y, z)))) {
+                let mut chunk = ChunkData::full_of_block(generator.stone);
+                generator.place_ores(&mut chunk, coords);
+
+                let clusters = count_clusters(&chunk, block);
+                let blocks = (0..CHUNK_VOLUME)
+                    .filter(|i| chunk.get_block(i % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE), i / CHUNK_SIZE % CHUNK_SIZE) == block)
+                    .count() as u32;
+                let in_range = coords.1 * size <= ore.height.1 && ore.height.0 < (coords.1 + 1) * size;
+
+                if in_range {
+                    assert!(clusters <= max_veins, "{} has {clusters} veins in {coords:?}", ore.block);
+                    assert!(min_veins == 0 || clusters >= 1, "{} has no vein in {coords:?}", ore.block);
+                    assert!(blocks <= max_veins * ore.vein_size.1, "{} has {blocks} blocks in {coords:?}", ore.block);
+                } else {
+                    assert_eq!(blocks, 0, "{} outside of its height range in {coords:?}", ore.block);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_ores() {
+        let registry = BlockRegistry::load("blocks.toml").unwrap();
+        let ore = |block: &str, height: &str, vein_size: &str, veins: &str| {
+            let source = format!("[[ore]]\nblock = \"{block}\"\nheight = {height}\nvein_size = {vein_size}\nveins_per_chunk = {veins}\n");
+            ores_from_toml(&source, &registry)
+        };
+
+        assert!(ore("coal_ore", "[0, 80]", "[6, 14]", "[1, 3]").is_ok());
+        assert!(ore("no_such_ore", "[0, 80]", "[6, 14]", "[1, 3]").is_err());
+        assert!(ore("coal_ore", "[80, 0]", "[6, 14]", "[1, 3]").is_err());
+        assert!(ore("coal_ore", "[0, 80]", "[14, 6]", "[1, 3]").is_err());
+        assert!(ore("coal_ore", "[0, 80]", "[6, 14]", "[3, 1]").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use crate::chunk::{BlockID, ChunkData};
+use crate::chunk_manager::CHUNK_SIZE;
+use crate::structure::PendingBlocks;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// A region file holds REGION_SIZE x REGION_SIZE chunks of one chunk layer (same chunk y).
+pub const REGION_SIZE: i32 = 32;
+const REGION_ENTRIES: usize = (REGION_SIZE * REGION_SIZE) as usize;
+// Per entry: offset (u32) and length (u32) of the chunk payload, 0 length = not saved
+const HEADER_SIZE: usize = REGION_ENTRIES * 8;
+
+const COMPRESSION_ZLIB: u8 = 1;
+
+// world/level.toml
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LevelInfo {
+    pub seed: u32,
+    pub spawn: [f32; 3],
+}
+
+// world/
+//   level.toml
+//   pending.dat
+//   region/r.<rx>.<cy>.<rz>.region
+//
+// Shared with the chunk workers, which load and save chunks in the background.
+pub struct WorldStorage {
+    dir: PathBuf,
+    // Held while a region is read and written back, so two saves can't drop each other's chunks.
+    // Loads don't need it: regions are replaced atomically.
+    region_writes: Mutex<()>,
+}
+
+impl WorldStorage {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<WorldStorage> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("region"))?;
+
+        Ok(WorldStorage { dir, region_writes: Mutex::new(()) })
+    }
+
+    fn level_path(&self) -> PathBuf {
+        self.dir.join("level.toml")
+    }
+
+    pub fn load_level(&self) -> io::Result<Option<LevelInfo>> {
+        let source = match fs::read_to_string(self.level_path()) {
+            Ok(source) => source,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        toml::from_str(&source)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    pub fn save_level(&self, level: &LevelInfo) -> io::Result<()> {
+        let source = toml::to_string(level).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        write_atomically(&self.level_path(), source.as_bytes())
+    }
+
+    fn pending_path(&self) -> PathBuf {
+        self.dir.join("pending.dat")
+    }
+
+    // Structure blocks still waiting for their chunk to be generated
+    pub fn load_pending(&self) -> io::Result<PendingBlocks> {
+        let compressed = match fs::read(self.pending_path()) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(PendingBlocks::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut bytes = Vec::new();
+        ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut bytes)?;
+
+        let mut input = bytes.as_slice();
+        let mut u8_buf = [0u8; 1];
+        let mut u16_buf = [0u8; 2];
+        let mut u32_buf = [0u8; 4];
+        let mut pending = PendingBlocks::new();
+
+        input.read_exact(&mut u32_buf)?;
+
+        for _ in 0..u32::from_le_bytes(u32_buf) {
+            let mut coords = [0i32; 3];
+            for c in coords.iter_mut() {
+                input.read_exact(&mut u32_buf)?;
+                *c = i32::from_le_bytes(u32_buf);
+            }
+
+            input.read_exact(&mut u32_buf)?;
+            let mut blocks = Vec::new();
+
+            for _ in 0..u32::from_le_bytes(u32_buf) {
+                let mut position = [0u32; 3];
+                for p in position.iter_mut() {
+                    input.read_exact(&mut u8_buf)?;
+                    *p = u8_buf[0] as u32;
+
+                    if *p >= CHUNK_SIZE {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "pending block out of its chunk"));
+                    }
+                }
+
+                input.read_exact(&mut u16_buf)?;
+                blocks.push(((position[0], position[1], position[2]), BlockID(u16::from_le_bytes(u16_buf))));
+            }
+
+            pending.insert((coords[0], coords[1], coords[2]), blocks);
+        }
+
+        Ok(pending)
+    }
+
+    // Layout: chunk count (u32), then per chunk its coordinates (3 x i32), block count (u32) and
+    // the blocks (x, y, z as u8, id as u16). Zlib compressed.
+    pub fn save_pending(&self, pending: &PendingBlocks) -> io::Result<()> {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&(pending.len() as u32).to_le_bytes());
+
+        for (&(x, y, z), blocks) in pending {
+            for c in [x, y, z] {
+                raw.extend_from_slice(&c.to_le_bytes());
+            }
+
+            raw.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+
+            for &((bx, by, bz), block) in blocks {
+                raw.extend_from_slice(&[bx as u8, by as u8, bz as u8]);
+                raw.extend_from_slice(&block.0.to_le_bytes());
+            }
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw)?;
+        write_atomically(&self.pending_path(), &encoder.finish()?)
+    }
+
+    // (region x, chunk y, region z) and the entry index inside that region
+    fn region_of((x, y, z): (i32, i32, i32)) -> ((i32, i32, i32), usize) {
+        let (rx, rz) = (x.div_euclid(REGION_SIZE), z.div_euclid(REGION_SIZE));
+        let (lx, lz) = (x.rem_euclid(REGION_SIZE), z.rem_euclid(REGION_SIZE));
+
+        ((rx, y, rz), (lz * REGION_SIZE + lx) as usize)
+    }
+
+    fn region_path(&self, (rx, cy, rz): (i32, i32, i32)) -> PathBuf {
+        self.dir.join("region").join(format!("r.{rx}.{cy}.{rz}.region"))
+    }
+
+    fn read_region_file(&self, region: (i32, i32, i32)) -> io::Result<Option<Vec<u8>>> {
+        let bytes = match fs::read(self.region_path(region)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated region header"));
+        }
+
+        Ok(Some(bytes))
+    }
+
+    // Compressed payload of every saved entry of a region
+    fn read_region(&self, region: (i32, i32, i32)) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let bytes = match self.read_region_file(region)? {
+            Some(bytes) => bytes,
+            None => return Ok(vec![None; REGION_ENTRIES]),
+        };
+
+        (0..REGION_ENTRIES)
+            .map(|i| region_entry(&bytes, i).map(|payload| payload.map(|p| p.to_vec())))
+            .collect()
+    }
+
+    fn write_region(&self, region: (i32, i32, i32), entries: &[Option<Vec<u8>>]) -> io::Result<()> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+
+        for (i, entry) in entries.iter().enumerate() {
+            if let Some(payload) = entry {
+                let offset = bytes.len() as u32;
+                bytes[i * 8..i * 8 + 4].copy_from_slice(&offset.to_le_bytes());
+                bytes[i * 8 + 4..i * 8 + 8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(payload);
+            }
+        }
+
+        write_atomically(&self.region_path(region), &bytes)
+    }
+
+    pub fn load_chunk(&self, coords: (i32, i32, i32)) -> io::Result<Option<ChunkData>> {
+        let (region, index) = WorldStorage::region_of(coords);
+
+        let bytes = match self.read_region_file(region)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        match region_entry(&bytes, index)? {
+            Some(payload) => decompress_chunk(payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Writes the chunks into their regions, keeping the other chunks already saved there
+    pub fn save_chunks<'a>(&self, chunks: impl Iterator<Item = ((i32, i32, i32), &'a ChunkData)>) -> io::Result<()> {
+        let mut regions: HashMap<(i32, i32, i32), Vec<(usize, &ChunkData)>> = HashMap::new();
+
+        for (coords, chunk) in chunks {
+            let (region, index) = WorldStorage::region_of(coords);
+            regions.entry(region).or_default().push((index, chunk));
+        }
+
+        let _writing = self.region_writes.lock().unwrap();
+
+        for (region, chunks) in regions {
+            let mut entries = self.read_region(region)?;
+
+            for (index, chunk) in chunks {
+                entries[index] = Some(compress_chunk(chunk)?);
+            }
+
+            self.write_region(region, &entries)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn region_entry(bytes: &[u8], index: usize) -> io::Result<Option<&[u8]>> {
+    let at = index * 8;
+    let offset = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    let length = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+
+    if length == 0 {
+        return Ok(None);
+    }
+
+    bytes
+        .get(offset..offset + length)
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "region entry out of bounds"))
+}
+
+// Payload: compression type (u8) followed by the compressed chunk
+fn compress_chunk(chunk: &ChunkData) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    chunk.write_to(&mut raw);
+
+    let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+    encoder.write_all(&raw)?;
+    encoder.finish()
+}
+
+fn decompress_chunk(payload: &[u8]) -> io::Result<ChunkData> {
+    match payload.split_first() {
+        Some((&COMPRESSION_ZLIB, compressed)) => {
+            let mut decoder = ZlibDecoder::new(compressed);
+            let chunk = ChunkData::read_from(&mut decoder)?;
+
+            // Nothing may follow the chunk
+            if decoder.read(&mut [0u8; 1])? != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "trailing bytes after chunk"));
+            }
+
+            Ok(chunk)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown chunk compression")),
+    }
+}
+
+// Write to a temporary file first so a crash never leaves a half-written region
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // World directory under the system temp dir, removed when dropped
+    struct TempWorld {
+        dir: PathBuf,
+        storage: WorldStorage,
+    }
+
+    impl TempWorld {
+        fn new(name: &str) -> TempWorld {
+            let dir = std::env::temp_dir().join(format!("r-minecraft-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let storage = WorldStorage::open(&dir).unwrap();
+
+            TempWorld { dir, storage }
+        }
+    }
+
+    impl Drop for TempWorld {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn chunk_bytes(chunk: &ChunkData) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        chunk.write_to(&mut bytes);
+        bytes
+    }
+
+    // A chunk that differs for every seed
+    fn test_chunk(seed: u16) -> ChunkData {
+        let mut chunk = ChunkData::empty();
+        for i in 0..CHUNK_SIZE {
+            chunk.set_block(i, (i + seed as u32) % CHUNK_SIZE, i / 2, BlockID(seed % 7 + i as u16 % 3));
+        }
+        chunk
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let world = TempWorld::new("chunks");
+        // Negative coordinates, several chunks of the same region and a chunk of another layer
+        let coords = [(-1, 0, -1), (-32, 0, -32), (-5, 0, -30), (0, 0, 0), (31, 0, 31), (-1, -2, -1), (40, 3, -70)];
+        let chunks: Vec<ChunkData> = (0..coords.len() as u16).map(test_chunk).collect();
+
+        world.storage.save_chunks(coords.iter().copied().zip(chunks.iter())).unwrap();
+
+        for (&c, chunk) in coords.iter().zip(&chunks) {
+            let loaded = world.storage.load_chunk(c).unwrap().unwrap_or_else(|| panic!("chunk {c:?} not saved"));
+            assert_eq!(chunk_bytes(&loaded), chunk_bytes(chunk), "chunk {c:?} differs");
+        }
+
+        assert!(world.storage.load_chunk((-2, 0, -1)).unwrap().is_none());
+        assert!(world.storage.load_chunk((-1, 1, -1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn saving_keeps_other_chunks_of_the_region() {
+        let world = TempWorld::new("keep");
+        let (first, second) = (test_chunk(1), test_chunk(2));
+
+        world.storage.save_chunks([((3, 0, -4), &first)].into_iter()).unwrap();
+        world.storage.save_chunks([((4, 0, -4), &second)].into_iter()).unwrap();
+        // Overwritten in place
+        world.storage.save_chunks([((4, 0, -4), &first)].into_iter()).unwrap();
+
+        let load = |c| chunk_bytes(&world.storage.load_chunk(c).unwrap().unwrap());
+        assert_eq!(load((3, 0, -4)), chunk_bytes(&first));
+        assert_eq!(load((4, 0, -4)), chunk_bytes(&first));
+    }
+
+    #[test]
+    fn level_round_trips() {
+        let world = TempWorld::new("level");
+        assert!(world.storage.load_level().unwrap().is_none());
+
+        let level = LevelInfo { seed: 0xDEAD_BEEF, spawn: [-12.5, 70.0, 3.25] };
+        world.storage.save_level(&level).unwrap();
+
+        let loaded = world.storage.load_level().unwrap().unwrap();
+        assert_eq!(loaded.seed, level.seed);
+        assert_eq!(loaded.spawn, level.spawn);
+    }
+
+    #[test]
+    fn pending_blocks_round_trip() {
+        let world = TempWorld::new("pending");
+        assert!(world.storage.load_pending().unwrap().is_empty());
+
+        let mut pending = PendingBlocks::new();
+        pending.insert((-3, 2, 7), vec![((0, 0, 0), BlockID(5)), ((15, 15, 15), BlockID(300))]);
+        pending.insert((10, -1, -10), vec![((4, 8, 12), BlockID(1))]);
+        world.storage.save_pending(&pending).unwrap();
+
+        assert_eq!(world.storage.load_pending().unwrap(), pending);
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let world = TempWorld::new("truncated");
+        let (region, _) = WorldStorage::region_of((0, 0, 0));
+        fs::write(world.storage.region_path(region), vec![0u8; HEADER_SIZE - 1]).unwrap();
+
+        let err = world.storage.load_chunk((0, 0, 0)).err().expect("truncated header accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_entry_out_of_bounds() {
+        let world = TempWorld::new("bounds");
+        let (region, index) = WorldStorage::region_of((1, 0, 0));
+
+        // The entry points past the end of the file
+        let mut bytes = vec![0u8; HEADER_SIZE + 4];
+        bytes[index * 8..index * 8 + 4].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        bytes[index * 8 + 4..index * 8 + 8].copy_from_slice(&100u32.to_le_bytes());
+        fs::write(world.storage.region_path(region), bytes).unwrap();
+
+        let err = world.storage.load_chunk((1, 0, 0)).err().expect("entry out of bounds accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // The other entries of the region are fine
+        assert!(world.storage.load_chunk((2, 0, 0)).unwrap().is_none());
+    }
+}